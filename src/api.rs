@@ -0,0 +1,585 @@
+/// Embedded HTTP API serving widget data straight from the ``bot__`` collections
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use futures_util::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::FindOptions,
+    Collection, Database,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::{
+    config, gis, history, html, ics,
+    models::{self, Platform},
+    png, query, svg, widget,
+};
+
+type ApiState = State<Arc<gis::Collections>>;
+
+pub enum ApiError {
+    NotFound,
+    BadRequest(String),
+    Internal(crate::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::Internal(e) => {
+                error!("API error: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal error".to_string(),
+                )
+            }
+        };
+
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+impl From<crate::Error> for ApiError {
+    fn from(e: crate::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<mongodb::error::Error> for ApiError {
+    fn from(e: mongodb::error::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+/// Finds all documents matching ``filter`` as a model
+async fn find_all<T>(
+    col: &Collection<Document>,
+    filter: Document,
+    options: impl Into<Option<FindOptions>>,
+) -> Result<Vec<T>, ApiError>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    Ok(col
+        .clone_with_type::<T>()
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?)
+}
+
+/// Finds a guild the bot is in, tombstoned guilds are treated as unknown
+async fn find_guild(cols: &gis::Collections, id: &str) -> Result<models::Server, ApiError> {
+    cols.server
+        .clone_with_type::<models::Server>()
+        .find_one(doc! {"id": id, "deleted_at": null}, None)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+fn to_json(documents: Vec<Document>) -> Value {
+    Value::Array(
+        documents
+            .into_iter()
+            .map(|d| Bson::Document(d).into_relaxed_extjson())
+            .collect(),
+    )
+}
+
+/// ``days`` ago from now
+fn days_ago(days: i64) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() - days * 24 * 60 * 60 * 1000)
+}
+
+async fn guild(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<models::Server>, ApiError> {
+    Ok(Json(find_guild(&cols, &id).await?))
+}
+
+#[derive(Deserialize)]
+struct MembersQuery {
+    /// Only members currently on this platform (desktop, mobile or web)
+    platform: Option<String>,
+    status: Option<String>,
+    /// Number of members returned, defaults to ``DEFAULT_MEMBER_LIMIT``
+    limit: Option<i64>,
+}
+
+const DEFAULT_MEMBER_LIMIT: i64 = 100;
+const MAX_MEMBER_LIMIT: i64 = 1000;
+
+async fn members(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<MembersQuery>,
+) -> Result<Json<Vec<models::User>>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    let platform = match q.platform {
+        Some(platform) => Some(
+            platform
+                .parse::<Platform>()
+                .map_err(|_| ApiError::BadRequest(format!("Unknown platform: {}", platform)))?,
+        ),
+        None => None,
+    };
+
+    let mut filter = query::members(&id, platform);
+
+    if let Some(status) = q.status {
+        filter.insert("status", status);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! {"name": 1})
+        .limit(
+            q.limit
+                .unwrap_or(DEFAULT_MEMBER_LIMIT)
+                .clamp(1, MAX_MEMBER_LIMIT),
+        )
+        .build();
+
+    Ok(Json(find_all(&cols.user, filter, options).await?))
+}
+
+async fn channels(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::Channels>>, ApiError> {
+    find_guild(&cols, &id).await?;
+    Ok(Json(
+        find_all(&cols.channel, query::public_channels(&id), None).await?,
+    ))
+}
+
+async fn roles(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::Role>>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    let options = FindOptions::builder().sort(doc! {"position": -1}).build();
+
+    Ok(Json(
+        find_all(&cols.role, doc! {"guild_id": &id}, options).await?,
+    ))
+}
+
+async fn voice(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::VoiceState>>, ApiError> {
+    find_guild(&cols, &id).await?;
+    Ok(Json(
+        find_all(&cols.voice, doc! {"guild_id": &id}, None).await?,
+    ))
+}
+
+async fn emojis(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::Emoji>>, ApiError> {
+    find_guild(&cols, &id).await?;
+    Ok(Json(
+        find_all(&cols.emoji, doc! {"guild_id": &id}, None).await?,
+    ))
+}
+
+async fn stickers(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::Sticker>>, ApiError> {
+    find_guild(&cols, &id).await?;
+    Ok(Json(
+        find_all(&cols.sticker, doc! {"guild_id": &id}, None).await?,
+    ))
+}
+
+async fn upcoming_events(
+    cols: &gis::Collections,
+    id: &str,
+) -> Result<Vec<models::Event>, ApiError> {
+    let options = FindOptions::builder().sort(doc! {"start_time": 1}).build();
+
+    find_all(&cols.event, query::upcoming_events(id), options).await
+}
+
+async fn events(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<models::Event>>, ApiError> {
+    find_guild(&cols, &id).await?;
+    Ok(Json(upcoming_events(&cols, &id).await?))
+}
+
+async fn events_ics(State(cols): ApiState, Path(id): Path<String>) -> Result<Response, ApiError> {
+    let server = find_guild(&cols, &id).await?;
+    let events = upcoming_events(&cols, &id).await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        ics::calendar(&server.name, &events),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct DaysQuery {
+    days: Option<i64>,
+}
+
+async fn daily_stats(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<DaysQuery>,
+) -> Result<Json<Value>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 0})
+        .sort(doc! {"date": -1})
+        .limit(q.days.unwrap_or(30))
+        .build();
+
+    let stats: Vec<Document> = find_all(&cols.stats_daily, doc! {"guild_id": &id}, options).await?;
+
+    Ok(Json(to_json(stats)))
+}
+
+async fn activity_peak(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<DaysQuery>,
+) -> Result<Json<Value>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    let peak = history::peak_online(&cols, &id, days_ago(q.days.unwrap_or(7))).await?;
+
+    Ok(Json(match peak {
+        Some((ts, online)) => json!({
+            "ts": ts.try_to_rfc3339_string().unwrap_or_default(),
+            "online": online,
+        }),
+        None => Value::Null,
+    }))
+}
+
+async fn activity_hourly(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<DaysQuery>,
+) -> Result<Json<Value>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    let series = history::online_series(&cols, &id, days_ago(q.days.unwrap_or(7))).await?;
+
+    Ok(Json(to_json(series)))
+}
+
+async fn activity_heatmap(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<DaysQuery>,
+) -> Result<Json<[[i64; 24]; 7]>, ApiError> {
+    find_guild(&cols, &id).await?;
+
+    Ok(Json(
+        history::heatmap(&cols, &id, days_ago(q.days.unwrap_or(28))).await?,
+    ))
+}
+
+/// Fetched cross-origin by embeds just like discord's, so any origin may read it
+async fn widget_json(State(cols): ApiState, Path(id): Path<String>) -> Result<Response, ApiError> {
+    let widget = widget::load(&cols, &id, widget::MAX_MEMBERS)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok((
+        [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Json(widget.to_json()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct ImageQuery {
+    theme: Option<String>,
+    size: Option<String>,
+    /// Maximum number of member avatars
+    members: Option<i64>,
+}
+
+impl ImageQuery {
+    fn options(&self, variant: svg::Variant) -> Result<svg::Options, ApiError> {
+        let theme = match &self.theme {
+            Some(theme) => theme
+                .parse::<svg::Theme>()
+                .map_err(|_| ApiError::BadRequest(format!("Unknown theme: {}", theme)))?,
+            None => svg::Theme::Dark,
+        };
+
+        let size = match &self.size {
+            Some(size) => size
+                .parse::<svg::Size>()
+                .map_err(|_| ApiError::BadRequest(format!("Unknown size: {}", size)))?,
+            None => svg::Size::Medium,
+        };
+
+        Ok(svg::Options {
+            variant,
+            theme,
+            size,
+        })
+    }
+
+    fn member_limit(&self, options: &svg::Options) -> i64 {
+        self.members
+            .unwrap_or_else(|| options.default_member_limit())
+            .clamp(0, options.max_member_limit())
+    }
+}
+
+async fn load_image(
+    cols: &gis::Collections,
+    id: &str,
+    q: &ImageQuery,
+    variant: svg::Variant,
+) -> Result<(widget::Widget, svg::Options), ApiError> {
+    let options = q.options(variant)?;
+
+    let widget = widget::load(cols, id, q.member_limit(&options))
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok((widget, options))
+}
+
+async fn svg_response(
+    cols: &gis::Collections,
+    id: &str,
+    q: &ImageQuery,
+    variant: svg::Variant,
+) -> Result<Response, ApiError> {
+    let (widget, options) = load_image(cols, id, q, variant).await?;
+    let (images, _) = svg::fetch_images(&widget).await;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "public, max-age=60"),
+        ],
+        svg::render(&widget, &images, &options),
+    )
+        .into_response())
+}
+
+async fn png_response(
+    cols: &gis::Collections,
+    id: &str,
+    q: &ImageQuery,
+    variant: svg::Variant,
+) -> Result<Response, ApiError> {
+    let (widget, options) = load_image(cols, id, q, variant).await?;
+    let key = png::cache_key(&widget, &options)?;
+
+    let image = match png::cached(key) {
+        Some(image) => image,
+        None => {
+            let (images, complete) = svg::fetch_images(&widget).await;
+            let image = png::rasterize(svg::render(&widget, &images, &options)).await?;
+
+            // The key doesn't cover the images, placeholders for failed fetches must not stick
+            if complete {
+                png::store(key, image.clone());
+            }
+
+            image
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=60"),
+        ],
+        image,
+    )
+        .into_response())
+}
+
+async fn widget_svg(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<ImageQuery>,
+) -> Result<Response, ApiError> {
+    svg_response(&cols, &id, &q, svg::Variant::Widget).await
+}
+
+async fn widget_png(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<ImageQuery>,
+) -> Result<Response, ApiError> {
+    png_response(&cols, &id, &q, svg::Variant::Widget).await
+}
+
+async fn banner_svg(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<ImageQuery>,
+) -> Result<Response, ApiError> {
+    svg_response(&cols, &id, &q, svg::Variant::Banner).await
+}
+
+async fn banner_png(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<ImageQuery>,
+) -> Result<Response, ApiError> {
+    png_response(&cols, &id, &q, svg::Variant::Banner).await
+}
+
+#[derive(Deserialize)]
+struct HtmlQuery {
+    theme: Option<String>,
+    /// Hex color, with or without the leading ``#``
+    accent: Option<String>,
+    /// Defaults to the preferred locale of the guild
+    locale: Option<String>,
+}
+
+fn parse_accent(accent: Option<&String>) -> Result<String, ApiError> {
+    match accent {
+        Some(accent) => html::parse_accent(accent.trim_start_matches('#'))
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid accent color: {}", accent))),
+        None => Ok(html::DEFAULT_ACCENT.to_string()),
+    }
+}
+
+async fn widget_html(
+    State(cols): ApiState,
+    Path(id): Path<String>,
+    Query(q): Query<HtmlQuery>,
+) -> Result<Response, ApiError> {
+    let theme = match &q.theme {
+        Some(theme) => theme
+            .parse::<html::Theme>()
+            .map_err(|_| ApiError::BadRequest(format!("Unknown theme: {}", theme)))?,
+        None => html::Theme::Auto,
+    };
+
+    let accent = parse_accent(q.accent.as_ref())?;
+
+    let widget = widget::load(&cols, &id, widget::MAX_MEMBERS)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let options = html::Options {
+        theme,
+        accent,
+        locale: q
+            .locale
+            .unwrap_or_else(|| widget.server.preferred_locale.clone()),
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                html::CONTENT_SECURITY_POLICY,
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::REFERRER_POLICY, "no-referrer"),
+            (header::CACHE_CONTROL, "public, max-age=60"),
+        ],
+        html::render(&widget, &options),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct CssQuery {
+    accent: Option<String>,
+}
+
+async fn widget_css(Query(q): Query<CssQuery>) -> Result<Response, ApiError> {
+    let accent = parse_accent(q.accent.as_ref())?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        html::stylesheet(&accent),
+    )
+        .into_response())
+}
+
+fn router(cols: gis::Collections) -> Router {
+    Router::new()
+        .route("/v1/guilds/:id", get(guild))
+        .route("/v1/guilds/:id/members", get(members))
+        .route("/v1/guilds/:id/channels", get(channels))
+        .route("/v1/guilds/:id/roles", get(roles))
+        .route("/v1/guilds/:id/voice", get(voice))
+        .route("/v1/guilds/:id/emojis", get(emojis))
+        .route("/v1/guilds/:id/stickers", get(stickers))
+        .route("/v1/guilds/:id/events", get(events))
+        .route("/v1/guilds/:id/events.ics", get(events_ics))
+        .route("/v1/guilds/:id/stats/daily", get(daily_stats))
+        .route("/v1/guilds/:id/activity/peak", get(activity_peak))
+        .route("/v1/guilds/:id/activity/hourly", get(activity_hourly))
+        .route("/v1/guilds/:id/activity/heatmap", get(activity_heatmap))
+        .route("/v1/guilds/:id/widget.json", get(widget_json))
+        // Same path as discord so existing embeds only need to swap the host
+        .route("/api/guilds/:id/widget.json", get(widget_json))
+        .route("/v1/guilds/:id/widget.svg", get(widget_svg))
+        .route("/v1/guilds/:id/widget.png", get(widget_png))
+        .route("/v1/guilds/:id/banner.svg", get(banner_svg))
+        .route("/v1/guilds/:id/banner.png", get(banner_png))
+        .route("/v1/guilds/:id/widget.html", get(widget_html))
+        .route("/v1/widget.css", get(widget_css))
+        .with_state(Arc::new(cols))
+}
+
+pub async fn serve(db: Database) {
+    let addr: SocketAddr = match config::CONFIG.api_bind.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid api_bind {}: {}", config::CONFIG.api_bind, e);
+            return;
+        }
+    };
+
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to bind API to {}: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("API listening on {}", addr);
+
+    if let Err(e) = server
+        .serve(router(gis::Collections::new(&db)).into_make_service())
+        .await
+    {
+        error!("API server error: {}", e);
+    }
+}
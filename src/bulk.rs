@@ -0,0 +1,218 @@
+/// Bulk upserts for seeding whole guilds at once
+///
+/// The mongodb driver does not expose bulk writes yet, so this issues the ``update`` command directly
+use std::collections::HashSet;
+
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Database,
+};
+use poise::serenity_prelude::GuildId;
+
+use crate::{config, state::MIRROR, Error};
+
+/// Totals for a bulk upsert across all of its batches
+#[derive(Default, Debug)]
+pub struct BulkResult {
+    pub matched: u64,
+    pub modified: u64,
+    pub upserted: u64,
+    pub errors: u64,
+    /// Documents not sent to mongo as they were unchanged
+    pub skipped: u64,
+    /// Filters of the documents mongo rejected, these were not written
+    pub failed: Vec<Document>,
+}
+
+/// Builds the filter of a document out of its key fields
+pub fn key_filter(document: &Document, keys: &[&str]) -> Result<Document, Error> {
+    let mut filter = Document::new();
+
+    for key in keys {
+        let value = document
+            .get(key)
+            .ok_or_else(|| format!("Document is missing key field: {}", key))?;
+
+        filter.insert(*key, value.clone());
+    }
+
+    Ok(filter)
+}
+
+/// Reads a count from a command reply, mongo may send these as either int32 or int64
+fn reply_count(reply: &Document, key: &str) -> u64 {
+    match reply.get(key) {
+        Some(Bson::Int32(n)) => *n as u64,
+        Some(Bson::Int64(n)) => *n as u64,
+        _ => 0,
+    }
+}
+
+/// Upserts all documents keyed by ``keys`` using unordered bulk writes of ``bulk_batch_size`` each
+///
+/// Documents the mirror shows as unchanged are not written at all
+pub async fn upsert_many(
+    db: &Database,
+    col: &Collection<Document>,
+    docs: Vec<Document>,
+    keys: &[&str],
+) -> Result<BulkResult, Error> {
+    let mut result = BulkResult::default();
+
+    let mut changed = Vec::with_capacity(docs.len());
+
+    for document in docs {
+        let filter = key_filter(&document, keys)?;
+
+        if MIRROR.unchanged(col.name(), &filter, &document) {
+            result.skipped += 1;
+        } else {
+            changed.push((filter, document));
+        }
+    }
+
+    for batch in changed.chunks(config::CONFIG.bulk_batch_size.max(1)) {
+        let updates = batch
+            .iter()
+            .map(|(filter, document)| {
+                doc! {
+                    "q": filter.clone(),
+                    "u": {"$set": document.clone()},
+                    "upsert": true,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let reply = db
+            .run_command(
+                doc! {
+                    "update": col.name(),
+                    "updates": updates,
+                    "ordered": false,
+                },
+                None,
+            )
+            .await?;
+
+        let upserted = reply
+            .get_array("upserted")
+            .map(|u| u.len() as u64)
+            .unwrap_or(0);
+
+        result.upserted += upserted;
+        result.matched += reply_count(&reply, "n").saturating_sub(upserted);
+        result.modified += reply_count(&reply, "nModified");
+
+        let mut failed = HashSet::new();
+
+        if let Ok(errors) = reply.get_array("writeErrors") {
+            for err in errors {
+                if let Some(index) = err.as_document().and_then(|e| e.get("index")) {
+                    match index {
+                        Bson::Int32(i) => failed.insert(*i as usize),
+                        Bson::Int64(i) => failed.insert(*i as usize),
+                        _ => false,
+                    };
+                }
+            }
+
+            if let Some(first) = errors.first() {
+                error!(
+                    "Bulk upsert had {} write errors (col={}), first: {}",
+                    errors.len(),
+                    col.name(),
+                    first
+                );
+            }
+
+            result.errors += errors.len() as u64;
+        }
+
+        for (i, (filter, document)) in batch.iter().enumerate() {
+            if failed.contains(&i) {
+                result.failed.push(filter.clone());
+            } else {
+                MIRROR.record(col.name(), filter, document.clone());
+            }
+        }
+    }
+
+    info!(
+        "Bulk upsert done (col={}): matched={}, modified={}, upserted={}, errors={}, skipped={}",
+        col.name(),
+        result.matched,
+        result.modified,
+        result.upserted,
+        result.errors,
+        result.skipped
+    );
+
+    Ok(result)
+}
+
+/// Deletes all documents of a guild whose id is not in ``keep``
+pub async fn prune(
+    col: &Collection<Document>,
+    guild_id: &str,
+    keep: &[String],
+) -> Result<u64, Error> {
+    let filter = doc! {"guild_id": guild_id, "id": {"$nin": keep}};
+
+    let stale = col.distinct("id", filter.clone(), None).await?;
+
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let res = col.delete_many(filter, None).await?;
+
+    for id in stale.iter().filter_map(|id| id.as_str()) {
+        MIRROR.forget(col.name(), &doc! {"id": id, "guild_id": guild_id});
+    }
+
+    info!(
+        "Pruned stale documents (col={}, gid={}): {}",
+        col.name(),
+        guild_id,
+        res.deleted_count
+    );
+
+    Ok(res.deleted_count)
+}
+
+/// Makes the documents of a guild in a collection match ``docs`` exactly
+///
+/// Documents are keyed by (id, guild_id), anything of the guild not in ``docs`` is deleted
+pub async fn replace_guild(
+    db: &Database,
+    col: &Collection<Document>,
+    guild_id: GuildId,
+    docs: Vec<Document>,
+) -> Result<(), Error> {
+    let keep = docs
+        .iter()
+        .filter_map(|d| d.get_str("id").ok().map(|id| id.to_string()))
+        .collect::<Vec<_>>();
+
+    upsert_many(db, col, docs, &["id", "guild_id"]).await?;
+    prune(col, &guild_id.to_string(), &keep).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_filter_picks_key_fields() {
+        let document = doc! {"id": "1", "guild_id": "2", "name": "a"};
+
+        assert_eq!(
+            key_filter(&document, &["id", "guild_id"]).unwrap(),
+            doc! {"id": "1", "guild_id": "2"}
+        );
+        assert!(key_filter(&document, &["id", "missing"]).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+/// Member chunking for large guilds
+///
+/// Discord only sends a partial member and precense list in the GuildCreate of large guilds.
+/// For those, all members are requested along with their precenses and every chunk that comes
+/// back is fed into the precense queue
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use mongodb::bson::doc;
+use poise::serenity_prelude::{
+    ChunkGuildFilter, Context as SerenityContext, GuildId, GuildMembersChunkEvent,
+};
+
+use crate::{cache::CacheHttpImpl, gis, writeback::PresenceQueue, Error};
+
+/// Chunking progress of a guild
+#[derive(Clone, Debug)]
+pub struct ChunkProgress {
+    pub received: u32,
+    pub members: u64,
+    pub precenses: u64,
+    pub started: Instant,
+    /// Time taken to receive all chunks
+    pub finished: Option<Duration>,
+}
+
+impl ChunkProgress {
+    fn new() -> Self {
+        Self {
+            received: 0,
+            members: 0,
+            precenses: 0,
+            started: Instant::now(),
+            finished: None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished.is_some()
+    }
+}
+
+#[derive(Default)]
+pub struct ChunkTracker {
+    progress: Mutex<HashMap<GuildId, ChunkProgress>>,
+}
+
+impl ChunkTracker {
+    /// Requests all members of a guild along with their precenses
+    pub fn request(&self, ctx: &SerenityContext, guild_id: GuildId) {
+        info!("Requesting member chunks: gid={}", guild_id);
+
+        self.progress
+            .lock()
+            .unwrap()
+            .insert(guild_id, ChunkProgress::new());
+
+        ctx.shard.chunk_guild(
+            guild_id,
+            None,
+            true,
+            ChunkGuildFilter::None,
+            Some(format!("diswidgets:{}", guild_id)),
+        );
+    }
+
+    /// Writes the precenses of a chunk and records its progress
+    pub async fn handle(
+        &self,
+        cache_http: &CacheHttpImpl,
+        cols: &gis::Collections,
+        presences: &PresenceQueue,
+        chunk: &GuildMembersChunkEvent,
+    ) -> Result<(), Error> {
+        let docs = {
+            let g = chunk
+                .guild_id
+                .to_guild_cached(&cache_http.cache)
+                .ok_or("Failed to get guild")?;
+
+            let mut docs = vec![];
+
+            for precense in chunk.presences.iter().flatten() {
+                // Chunk precenses only carry the user id, the user comes with the chunk members
+                let bson = match chunk.members.get(&precense.user.id) {
+                    Some(member) => gis::member_precense(&g, &member.user, precense),
+                    None => gis::user_precense(&g, precense),
+                };
+
+                match bson.and_then(gis::into_document) {
+                    Ok(document) => docs.push((precense.user.id, document)),
+                    Err(e) => error!("Failed to create bson document for precense: {}", e),
+                }
+            }
+
+            docs
+        };
+
+        let precenses = docs.len() as u64;
+
+        for (user_id, document) in docs {
+            presences.push(chunk.guild_id, user_id, document);
+        }
+
+        let done = {
+            let mut progress = self.progress.lock().unwrap();
+            let p = progress
+                .entry(chunk.guild_id)
+                .or_insert_with(ChunkProgress::new);
+
+            p.received += 1;
+            p.members += chunk.members.len() as u64;
+            p.precenses += precenses;
+
+            if p.received >= chunk.chunk_count && !p.is_done() {
+                p.finished = Some(p.started.elapsed());
+                Some(p.clone())
+            } else {
+                None
+            }
+        };
+
+        if let Some(p) = done {
+            info!(
+                "Finished chunking guild: gid={}, chunks={}, members={}, precenses={}, took={:?}",
+                chunk.guild_id,
+                p.received,
+                p.members,
+                p.precenses,
+                p.finished.unwrap_or_default()
+            );
+
+            // Everyone online was part of a chunk
+            presences.expire_stale(cols, chunk.guild_id).await;
+
+            // The member count fallbacks are accurate now that all members are cached
+            gis::add_or_update(
+                &cols.server,
+                doc! {"id": chunk.guild_id.to_string()},
+                gis::guild(cache_http, chunk.guild_id)?,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of guilds with chunks still outstanding
+    pub fn in_progress(&self) -> usize {
+        self.progress
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|p| !p.is_done())
+            .count()
+    }
+}
@@ -0,0 +1,87 @@
+/// Hard deletion of guilds the bot was removed from
+///
+/// A GuildDelete only tombstones the server document, the guild and all of its documents
+/// are deleted once the tombstone is older than ``guild_delete_grace_secs``
+use std::time::Duration;
+
+use log::{error, info};
+use mongodb::{
+    bson::{doc, DateTime},
+    Database,
+};
+
+use crate::{config, gis, state::MIRROR, Error};
+
+/// How often tombstones are checked
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Deletes every guild whose tombstone has outlived the grace period
+pub async fn sweep(cols: &gis::Collections) -> Result<(), Error> {
+    let cutoff = DateTime::from_millis(
+        DateTime::now().timestamp_millis() - (config::CONFIG.guild_delete_grace_secs * 1000) as i64,
+    );
+
+    let guild_ids = cols
+        .server
+        .distinct("id", doc! {"deleted_at": {"$lte": cutoff}}, None)
+        .await?;
+
+    for guild_id in guild_ids {
+        let guild_id = match guild_id.as_str() {
+            Some(guild_id) => guild_id.to_string(),
+            None => continue,
+        };
+
+        info!("Deleting tombstoned guild: gid={}", guild_id);
+
+        cols.user
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.channel
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.role
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.voice
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.emoji
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.sticker
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.event
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.stats_daily
+            .delete_many(doc! {"guild_id": &guild_id}, None)
+            .await?;
+        cols.server.delete_one(doc! {"id": &guild_id}, None).await?;
+
+        MIRROR.forget_where(cols.user.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.channel.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.role.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.voice.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.emoji.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.sticker.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.event.name(), "guild_id", &guild_id);
+        MIRROR.forget_where(cols.server.name(), "id", &guild_id);
+    }
+
+    Ok(())
+}
+
+pub async fn run(db: Database) {
+    let cols = gis::Collections::new(&db);
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = sweep(&cols).await {
+            error!("Failed to sweep tombstoned guilds: {}", e);
+        }
+    }
+}
@@ -8,10 +8,39 @@ use crate::Error;
 pub static CONFIG: Lazy<Config> = Lazy::new(|| Config::load().expect("Failed to load config"));
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub mongodb_url: String,
     pub token: String,
     pub proxy_url: String,
+    /// Number of upserts sent to mongo in a single bulk write
+    pub bulk_batch_size: usize,
+    /// How often queued precense updates are flushed to mongo
+    pub presence_flush_interval_ms: u64,
+    /// Queue depth at which precense updates are flushed without waiting for the timer
+    pub presence_flush_threshold: usize,
+    /// How long a guild the bot was removed from is kept before it is deleted
+    pub guild_delete_grace_secs: u64,
+    /// How often member counts are corrected from discord
+    pub member_count_refresh_secs: u64,
+    /// How often the per status counters are recounted from the stored members
+    pub status_recount_secs: u64,
+    /// How long presence history is kept
+    pub presence_history_retention_days: u64,
+    /// How often daily stats are rolled up, this is also the voice minute sampling interval
+    pub stats_rollup_interval_secs: u64,
+    /// How often stored documents are reconciled with the cache
+    pub reconcile_interval_secs: u64,
+    /// Maximum number of guilds reconciled at the same time
+    pub reconcile_max_concurrency: usize,
+    /// Address the API listens on
+    pub api_bind: String,
+    /// Maximum number of rendered PNG images kept in memory
+    pub png_cache_max_entries: usize,
+    /// Maximum number of guild icons and avatars kept in memory for rendering
+    pub image_cache_max_entries: usize,
+    /// How long a fetched guild icon or avatar is reused before it is fetched again
+    pub image_cache_ttl_secs: u64,
 }
 
 impl Default for Config {
@@ -20,6 +49,20 @@ impl Default for Config {
             token: String::from(""),
             mongodb_url: String::from(""),
             proxy_url: String::from("http://127.0.0.1:3219"),
+            bulk_batch_size: 1000,
+            presence_flush_interval_ms: 2000,
+            presence_flush_threshold: 1000,
+            guild_delete_grace_secs: 60 * 60 * 24,
+            member_count_refresh_secs: 60 * 60 * 6,
+            status_recount_secs: 60 * 15,
+            presence_history_retention_days: 30,
+            stats_rollup_interval_secs: 60 * 5,
+            reconcile_interval_secs: 60 * 60,
+            reconcile_max_concurrency: 2,
+            api_bind: String::from("127.0.0.1:3010"),
+            png_cache_max_entries: 512,
+            image_cache_max_entries: 4096,
+            image_cache_ttl_secs: 60 * 60,
         }
     }
 }
@@ -0,0 +1,330 @@
+/// Counters kept on the server document
+///
+/// ``member_count`` is moved by member join and leave events and is periodically corrected
+/// using the approximate member count from discord.
+///
+/// ``status_counts`` is moved whenever a user document is written with a different status
+/// than the last written one, and is periodically recounted from ``bot__server_user``
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Database,
+};
+use poise::serenity_prelude::GuildId;
+
+use crate::{
+    bulk, cache::CacheHttpImpl, config, gis, history, state::MIRROR, writeback::PresenceQueue,
+    Error,
+};
+
+/// Statuses that have a counter
+const COUNTED_STATUSES: [&str; 3] = ["online", "idle", "dnd"];
+
+/// Status a user document is counted under, stale precenses aren't counted until confirmed
+fn counted(document: &Document) -> Option<&str> {
+    if document.get_bool("stale").unwrap_or(false) {
+        return None;
+    }
+
+    document.get_str("status").ok()
+}
+
+/// Per guild changes to the status counters
+type StatusDeltas = HashMap<String, HashMap<&'static str, i64>>;
+
+fn add_delta(deltas: &mut StatusDeltas, guild_id: &str, status: Option<&str>, delta: i64) {
+    if let Some(status) = status.and_then(|s| COUNTED_STATUSES.iter().copied().find(|c| *c == s)) {
+        *deltas
+            .entry(guild_id.to_string())
+            .or_default()
+            .entry(status)
+            .or_default() += delta;
+    }
+}
+
+/// Applies status counter changes with one ``$inc`` per guild
+async fn apply_status_deltas(cols: &gis::Collections, deltas: StatusDeltas) -> Result<(), Error> {
+    for (guild_id, deltas) in deltas {
+        let mut inc = Document::new();
+
+        for (status, delta) in deltas {
+            if delta != 0 {
+                inc.insert(format!("status_counts.{}", status), delta);
+            }
+        }
+
+        if inc.is_empty() {
+            continue;
+        }
+
+        cols.server
+            .update_one(doc! {"id": guild_id}, doc! {"$inc": inc}, None)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Upserts user documents, moving the status counters of their guilds along
+///
+/// The previous status of each user is taken from the mirror, so this must be the only way
+/// user documents are bulk written
+pub async fn upsert_users(cols: &gis::Collections, docs: Vec<Document>) -> Result<(), Error> {
+    // Previous statuses have to be read before the write updates the mirror
+    let mut changes = vec![];
+
+    for document in docs.iter() {
+        let filter = bulk::key_filter(document, &["id", "guild_id"])?;
+        let last = MIRROR.get(cols.user.name(), &filter);
+
+        let old = last.as_ref().and_then(|d| d.get_str("status").ok());
+        let new = document.get_str("status").ok();
+        let old_counted = last.as_ref().and_then(counted);
+        let new_counted = counted(document);
+
+        if old != new || old_counted != new_counted {
+            let owned = |s: Option<&str>| s.map(|s| s.to_string());
+
+            changes.push((
+                filter,
+                (owned(old), owned(new)),
+                (owned(old_counted), owned(new_counted)),
+            ));
+        }
+    }
+
+    let result = bulk::upsert_many(&cols.db, &cols.user, docs, &["id", "guild_id"]).await?;
+
+    let mut deltas = StatusDeltas::new();
+    let mut transitions = vec![];
+
+    // Rejected documents keep their old status, so they must not move the counters
+    for (filter, (old, new), (old_counted, new_counted)) in changes {
+        if result.failed.contains(&filter) {
+            continue;
+        }
+
+        let guild_id = filter.get_str("guild_id")?;
+
+        add_delta(&mut deltas, guild_id, old_counted.as_deref(), -1);
+        add_delta(&mut deltas, guild_id, new_counted.as_deref(), 1);
+
+        if old != new {
+            transitions.push(history::transition(
+                guild_id,
+                filter.get_str("id")?,
+                old.as_deref(),
+                new.as_deref(),
+            ));
+        }
+    }
+
+    if let Err(e) = history::record(cols, transitions).await {
+        error!("Failed to record status transitions: {}", e);
+    }
+
+    apply_status_deltas(cols, deltas).await
+}
+
+/// Deletes a user document, moving the status counters of its guild along
+pub async fn delete_user(cols: &gis::Collections, filter: Document) -> Result<(), Error> {
+    let guild_id = filter.get_str("guild_id")?.to_string();
+    let last = MIRROR.get(cols.user.name(), &filter);
+
+    cols.user.delete_one(filter.clone(), None).await?;
+    MIRROR.forget(cols.user.name(), &filter);
+
+    let mut deltas = StatusDeltas::new();
+    add_delta(&mut deltas, &guild_id, last.as_ref().and_then(counted), -1);
+
+    apply_status_deltas(cols, deltas).await
+}
+
+/// Marks every precense of a guild as stale, until fresh guild data confirms it
+///
+/// Stale precenses are not counted, so they are taken off the counters here. Flushes are held
+/// off meanwhile, as they compare against the same mirror entries
+pub async fn mark_stale(
+    cols: &gis::Collections,
+    presences: &PresenceQueue,
+    guild_id: GuildId,
+) -> Result<(), Error> {
+    let _paused = presences.pause().await;
+    let gid = guild_id.to_string();
+
+    cols.user
+        .update_many(
+            doc! {"guild_id": &gid},
+            doc! {"$set": {"stale": true}},
+            None,
+        )
+        .await?;
+
+    let mut deltas = StatusDeltas::new();
+
+    for document in MIRROR.find(cols.user.name(), |d| {
+        d.get_str("guild_id") == Ok(gid.as_str())
+    }) {
+        add_delta(&mut deltas, &gid, counted(&document), -1);
+    }
+
+    // Keep the mirror in line, the previous status is still needed for history
+    MIRROR.set_where(
+        cols.user.name(),
+        "guild_id",
+        &gid,
+        "stale",
+        Bson::Boolean(true),
+    );
+
+    apply_status_deltas(cols, deltas).await
+}
+
+/// Recounts the status counters of every guild from the stored user documents
+pub async fn recount_statuses(cols: &gis::Collections) -> Result<(), Error> {
+    let mut cursor = cols
+        .user
+        .aggregate(
+            vec![
+                doc! {"$match": {
+                    "status": {"$in": COUNTED_STATUSES.to_vec()},
+                    "stale": {"$ne": true},
+                }},
+                doc! {"$group": {
+                    "_id": {"guild_id": "$guild_id", "status": "$status"},
+                    "count": {"$sum": 1i64},
+                }},
+            ],
+            None,
+        )
+        .await?;
+
+    let mut counts: HashMap<String, Document> = HashMap::new();
+
+    while let Some(group) = cursor.try_next().await? {
+        let id = group.get_document("_id")?;
+        let count = match group.get("count") {
+            Some(count) => count.clone(),
+            None => continue,
+        };
+
+        counts
+            .entry(id.get_str("guild_id")?.to_string())
+            .or_insert_with(|| doc! {"online": 0i64, "idle": 0i64, "dnd": 0i64})
+            .insert(id.get_str("status")?, count);
+    }
+
+    let guild_ids = counts.keys().cloned().collect::<Vec<_>>();
+
+    for (guild_id, status_counts) in counts {
+        cols.server
+            .update_one(
+                doc! {"id": guild_id},
+                doc! {"$set": {"status_counts": status_counts}},
+                None,
+            )
+            .await?;
+    }
+
+    // Guilds without a single counted member
+    cols.server
+        .update_many(
+            doc! {"id": {"$nin": guild_ids}},
+            doc! {"$set": {"status_counts": {"online": 0i64, "idle": 0i64, "dnd": 0i64}}},
+            None,
+        )
+        .await?;
+
+    info!("Recounted member statuses");
+
+    Ok(())
+}
+
+/// Adds ``delta`` to the stored member count of a guild
+pub async fn add_members(cols: &gis::Collections, guild_id: &str, delta: i64) -> Result<(), Error> {
+    let filter = doc! {"id": guild_id};
+
+    cols.server
+        .update_one(filter.clone(), doc! {"$inc": {"member_count": delta}}, None)
+        .await?;
+
+    // The mirrored document no longer matches what is stored
+    MIRROR.forget(cols.server.name(), &filter);
+
+    Ok(())
+}
+
+/// Resets the member count of every cached guild from discord
+pub async fn correct_member_counts(
+    cache_http: &CacheHttpImpl,
+    cols: &gis::Collections,
+) -> Result<(), Error> {
+    for guild_id in cache_http.cache.guilds() {
+        let guild = match guild_id
+            .to_partial_guild_with_counts(&cache_http.http)
+            .await
+        {
+            Ok(guild) => guild,
+            Err(e) => {
+                error!("Failed to fetch member count: gid={}, err={}", guild_id, e);
+                continue;
+            }
+        };
+
+        let member_count = match guild.approximate_member_count {
+            Some(member_count) => member_count,
+            None => continue,
+        };
+
+        let filter = doc! {"id": guild_id.to_string()};
+
+        cols.server
+            .update_one(
+                filter.clone(),
+                doc! {"$set": {"member_count": member_count as i64}},
+                None,
+            )
+            .await?;
+
+        MIRROR.forget(cols.server.name(), &filter);
+    }
+
+    info!("Corrected member counts");
+
+    Ok(())
+}
+
+pub async fn run(cache_http: CacheHttpImpl, db: Database) {
+    let cols = gis::Collections::new(&db);
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config::CONFIG.member_count_refresh_secs,
+    ));
+
+    // The first tick completes immediately, guilds are freshly snapshotted at that point anyways
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = correct_member_counts(&cache_http, &cols).await {
+            error!("Failed to correct member counts: {}", e);
+        }
+    }
+}
+
+pub async fn run_recount(db: Database) {
+    let cols = gis::Collections::new(&db);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config::CONFIG.status_recount_secs));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = recount_statuses(&cols).await {
+            error!("Failed to recount member statuses: {}", e);
+        }
+    }
+}
@@ -3,39 +3,333 @@
 /// It stands for "Guild Information Setup"
 use log::{error, info};
 use mongodb::{
-    bson::{self, doc, Bson, Document},
-    Collection,
+    bson::{self, doc, Bson, DateTime, Document},
+    options::UpdateOptions,
+    Collection, Database,
 };
-use poise::serenity_prelude::{GuildId, OnlineStatus, Presence};
+use poise::serenity_prelude::{
+    Activity, ActivityType, Emoji, Guild, GuildChannel, GuildId, OnlineStatus,
+    PermissionOverwriteType, PremiumTier, Presence, Role, RoleId, ScheduledEvent,
+    ScheduledEventStatus, Sticker, StickerFormatType, Timestamp, User, UserId, VoiceState,
+};
+
+use crate::{bulk, cache::CacheHttpImpl, state::MIRROR, writeback::PresenceQueue, Error};
+
+/// The ``bot__`` collections the bot writes to
+pub struct Collections {
+    pub db: Database,
+    pub server: Collection<Document>,
+    pub user: Collection<Document>,
+    pub channel: Collection<Document>,
+    pub role: Collection<Document>,
+    pub voice: Collection<Document>,
+    pub emoji: Collection<Document>,
+    pub sticker: Collection<Document>,
+    pub event: Collection<Document>,
+    pub presence_history: Collection<Document>,
+    pub presence_hourly: Collection<Document>,
+    pub stats_daily: Collection<Document>,
+}
 
-use crate::{cache::CacheHttpImpl, Error};
+impl Collections {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            db: db.clone(),
+            server: db.collection::<Document>("bot__server_info"),
+            user: db.collection::<Document>("bot__server_user"),
+            channel: db.collection::<Document>("bot__server_channel"),
+            role: db.collection::<Document>("bot__server_role"),
+            voice: db.collection::<Document>("bot__server_voice"),
+            emoji: db.collection::<Document>("bot__server_emoji"),
+            sticker: db.collection::<Document>("bot__server_sticker"),
+            event: db.collection::<Document>("bot__server_event"),
+            presence_history: db.collection::<Document>("bot__server_presence_history"),
+            presence_hourly: db.collection::<Document>("bot__server_presence_hourly"),
+            stats_daily: db.collection::<Document>("bot__server_stats_daily"),
+        }
+    }
+}
+
+/// Builds the user document of a precense
+///
+/// Precenses may only carry a partial user, the full user is then taken from the cached member
+pub fn user_precense(g: &Guild, p: &Presence) -> Result<Bson, Error> {
+    let user = p
+        .user
+        .to_user()
+        .or_else(|| g.members.get(&p.user.id).map(|m| m.user.clone()))
+        .ok_or("Failed to get user")?;
+
+    member_precense(g, &user, p)
+}
 
-pub fn user_precense(guild_id: GuildId, p: &Presence) -> Result<Bson, Error> {
-    let user = p.user.to_user().ok_or("Failed to get user")?;
+/// Builds the user document of a precense whose full user is already known
+pub fn member_precense(g: &Guild, user: &User, p: &Presence) -> Result<Bson, Error> {
+    let (roles, hoisted_role) = member_roles(g, user.id);
 
     Ok(bson::to_bson(&crate::models::User {
         id: user.id.to_string(),
-        guild_id: guild_id.to_string(),
+        guild_id: g.id.to_string(),
         name: user.name.clone(),
         discriminator: format!("{:.04}", user.discriminator),
         avatar: user
             .avatar_url()
             .unwrap_or("https://cdn.discordapp.com/embed/avatars/0.png".to_string()),
-        status: match p.status {
-            OnlineStatus::Online => "online",
-            OnlineStatus::Idle => "idle",
-            OnlineStatus::DoNotDisturb => "dnd",
-            OnlineStatus::Offline => "offline",
-            OnlineStatus::Invisible => "invisible",
+        status: status(p.status).to_string(),
+        client_status: p
+            .client_status
+            .as_ref()
+            .map(|c| crate::models::ClientStatus {
+                desktop: c.desktop.map(|s| status(s).to_string()),
+                mobile: c.mobile.map(|s| status(s).to_string()),
+                web: c.web.map(|s| status(s).to_string()),
+            })
+            .unwrap_or_default(),
+        activities: p.activities.iter().map(activity).collect(),
+        roles,
+        hoisted_role,
+        stale: false,
+        updated_at: Some(DateTime::now()),
+    })?)
+}
+
+/// Returns the role ids of a member along with their highest hoisted role
+pub fn member_roles(g: &Guild, user_id: UserId) -> (Vec<String>, Option<String>) {
+    let member = match g.members.get(&user_id) {
+        Some(member) => member,
+        None => return (vec![], None),
+    };
+
+    // Roles missing from the guild were deleted, the member list may not have caught up yet
+    let roles = member
+        .roles
+        .iter()
+        .filter_map(|id| g.roles.get(id))
+        .collect::<Vec<_>>();
+
+    let hoisted_role = roles
+        .iter()
+        .filter(|r| r.hoist)
+        .max_by_key(|r| r.position)
+        .map(|r| r.id.to_string());
+
+    (
+        roles.iter().map(|r| r.id.to_string()).collect(),
+        hoisted_role,
+    )
+}
+
+/// Builds the current document of a member, for when only their roles changed
+///
+/// Members with a cached precense are rebuilt from it, otherwise the last written document gets
+/// its roles replaced. Members that were never written are skipped
+pub fn member_document(
+    cols: &Collections,
+    g: &Guild,
+    user_id: UserId,
+) -> Result<Option<Document>, Error> {
+    if let Some(precense) = g.presences.get(&user_id) {
+        return user_precense(g, precense).and_then(into_document).map(Some);
+    }
+
+    let filter = doc! {"id": user_id.to_string(), "guild_id": g.id.to_string()};
+
+    let mut document = match MIRROR.get(cols.user.name(), &filter) {
+        Some(document) => document,
+        None => return Ok(None),
+    };
+
+    let (roles, hoisted_role) = member_roles(g, user_id);
+
+    document.insert("roles", roles);
+    document.insert("hoisted_role", hoisted_role);
+    document.insert("updated_at", DateTime::now());
+
+    Ok(Some(document))
+}
+
+/// Ids of the members holding a role, from the cache and from what was last written
+pub fn role_holders(cols: &Collections, g: &Guild, role_id: RoleId) -> Vec<UserId> {
+    let guild_id = g.id.to_string();
+    let role = Bson::String(role_id.to_string());
+
+    let mut user_ids = g
+        .members
+        .values()
+        .filter(|m| m.roles.contains(&role_id))
+        .map(|m| m.user.id)
+        .collect::<Vec<_>>();
+
+    let stored = MIRROR.find(cols.user.name(), |d| {
+        d.get_str("guild_id") == Ok(guild_id.as_str())
+            && matches!(d.get_array("roles"), Ok(roles) if roles.contains(&role))
+    });
+
+    for document in stored {
+        if let Some(user_id) = document
+            .get_str("id")
+            .ok()
+            .and_then(|id| id.parse::<u64>().ok())
+            .map(UserId::new)
+        {
+            if !user_ids.contains(&user_id) {
+                user_ids.push(user_id);
+            }
+        }
+    }
+
+    user_ids
+}
+
+/// Queues the rebuilt documents of members whose roles or hoisted role may have changed
+pub fn refresh_members(
+    cols: &Collections,
+    presences: &PresenceQueue,
+    g: &Guild,
+    user_ids: Vec<UserId>,
+) {
+    for user_id in user_ids {
+        match member_document(cols, g, user_id) {
+            Ok(Some(document)) => presences.push(g.id, user_id, document),
+            Ok(None) => {}
+            Err(e) => error!("Failed to create bson document for member: {}", e),
+        }
+    }
+}
+
+pub fn voice_state(guild_id: GuildId, v: &VoiceState) -> Result<Bson, Error> {
+    let channel_id = v.channel_id.ok_or("Voice state without channel")?;
+
+    Ok(bson::to_bson(&crate::models::VoiceState {
+        id: v.user_id.to_string(),
+        guild_id: guild_id.to_string(),
+        channel_id: channel_id.to_string(),
+        mute: v.mute,
+        deaf: v.deaf,
+        self_mute: v.self_mute,
+        self_deaf: v.self_deaf,
+        self_stream: v.self_stream.unwrap_or(false),
+        self_video: v.self_video,
+        suppress: v.suppress,
+    })?)
+}
+
+pub fn emoji(guild_id: GuildId, e: &Emoji) -> Result<Bson, Error> {
+    Ok(bson::to_bson(&crate::models::Emoji {
+        id: e.id.to_string(),
+        guild_id: guild_id.to_string(),
+        name: e.name.clone(),
+        url: e.url(),
+        animated: e.animated,
+        available: e.available,
+        managed: e.managed,
+    })?)
+}
+
+pub fn sticker(guild_id: GuildId, st: &Sticker) -> Result<Bson, Error> {
+    Ok(bson::to_bson(&crate::models::Sticker {
+        id: st.id.to_string(),
+        guild_id: guild_id.to_string(),
+        name: st.name.clone(),
+        description: st.description.clone(),
+        tags: st.tags.clone(),
+        format: match st.format_type {
+            StickerFormatType::Png => "png",
+            StickerFormatType::Apng => "apng",
+            StickerFormatType::Lottie => "lottie",
+            StickerFormatType::Gif => "gif",
             _ => "unknown",
         }
         .to_string(),
+        url: st.image_url(),
+        available: st.available,
+    })?)
+}
+
+pub fn scheduled_event(e: &ScheduledEvent) -> Result<Bson, Error> {
+    Ok(bson::to_bson(&crate::models::Event {
+        id: e.id.to_string(),
+        guild_id: e.guild_id.to_string(),
+        name: e.name.clone(),
+        description: e.description.clone(),
+        start_time: timestamp(e.start_time),
+        end_time: e.end_time.map(timestamp),
+        location: e.metadata.as_ref().map(|m| m.location.clone()),
+        channel_id: e.channel_id.map(|id| id.to_string()),
+        status: match e.status {
+            ScheduledEventStatus::Scheduled => "scheduled",
+            ScheduledEventStatus::Active => "active",
+            ScheduledEventStatus::Completed => "completed",
+            ScheduledEventStatus::Canceled => "canceled",
+            _ => "unknown",
+        }
+        .to_string(),
+        user_count: e.user_count,
+    })?)
+}
+
+fn timestamp(ts: Timestamp) -> DateTime {
+    DateTime::from_millis(ts.unix_timestamp() * 1000)
+}
+
+pub fn role(r: &Role) -> Result<Bson, Error> {
+    Ok(bson::to_bson(&crate::models::Role {
+        id: r.id.to_string(),
+        guild_id: r.guild_id.to_string(),
+        name: r.name.clone(),
+        color: r.colour.0,
+        position: i64::from(r.position),
+        hoist: r.hoist,
+        icon: r.icon.as_ref().map(|hash| {
+            format!(
+                "https://cdn.discordapp.com/role-icons/{}/{}.png",
+                r.id, hash
+            )
+        }),
+        unicode_emoji: r.unicode_emoji.clone(),
     })?)
 }
 
+fn status(s: OnlineStatus) -> &'static str {
+    match s {
+        OnlineStatus::Online => "online",
+        OnlineStatus::Idle => "idle",
+        OnlineStatus::DoNotDisturb => "dnd",
+        OnlineStatus::Offline => "offline",
+        OnlineStatus::Invisible => "invisible",
+        _ => "unknown",
+    }
+}
+
+fn activity(a: &Activity) -> crate::models::Activity {
+    crate::models::Activity {
+        kind: match a.kind {
+            ActivityType::Playing => "playing",
+            ActivityType::Streaming => "streaming",
+            ActivityType::Listening => "listening",
+            ActivityType::Watching => "watching",
+            ActivityType::Custom => "custom",
+            ActivityType::Competing => "competing",
+            _ => "unknown",
+        }
+        .to_string(),
+        name: a.name.clone(),
+        details: a.details.clone(),
+        state: a.state.clone(),
+        url: a.url.as_ref().map(|u| u.to_string()),
+        started_at: a.timestamps.as_ref().and_then(|t| t.start),
+        ends_at: a.timestamps.as_ref().and_then(|t| t.end),
+        emoji: a.emoji.as_ref().map(|e| crate::models::ActivityEmoji {
+            name: e.name.clone(),
+            id: e.id.map(|id| id.to_string()),
+            animated: e.animated.unwrap_or(false),
+        }),
+    }
+}
+
 pub fn guild(cache_http: &CacheHttpImpl, guild_id: GuildId) -> Result<Bson, Error> {
     // Try to find guild in either cache or http
-    let (name, icon, member_count) = {
+    let server = {
         let g = guild_id.to_guild_cached(&cache_http.cache).ok_or_else(|| {
             error!("Guild not found in cache: gid={}", guild_id);
             "Guild not found in cache"
@@ -51,47 +345,333 @@ pub fn guild(cache_http: &CacheHttpImpl, guild_id: GuildId) -> Result<Bson, Erro
             }
         };
 
-        (
-            g.name.clone(),
-            g.icon_url()
+        let has_feature = |feature: &str| g.features.iter().any(|f| f == feature);
+
+        crate::models::Server {
+            id: guild_id.to_string(),
+            name: g.name.clone(),
+            icon: g
+                .icon_url()
                 .unwrap_or("https://cdn.discordapp.com/embed/avatars/0.png".to_string()),
             member_count,
-        )
+            banner: g.banner_url(),
+            splash: g.splash_url(),
+            description: g.description.clone(),
+            premium_tier: match g.premium_tier {
+                PremiumTier::Tier1 => 1,
+                PremiumTier::Tier2 => 2,
+                PremiumTier::Tier3 => 3,
+                _ => 0,
+            },
+            premium_subscription_count: g.premium_subscription_count,
+            vanity_url_code: g.vanity_url_code.clone(),
+            verified: has_feature("VERIFIED"),
+            partnered: has_feature("PARTNERED"),
+            community: has_feature("COMMUNITY"),
+            features: g.features.clone(),
+            preferred_locale: g.preferred_locale.clone(),
+            created_at: Some(timestamp(guild_id.created_at())),
+            status_counts: None,
+            deleted_at: None,
+        }
     };
 
-    Ok(bson::to_bson(&crate::models::Server {
-        id: guild_id.to_string(),
-        name,
-        icon,
-        member_count,
+    Ok(bson::to_bson(&server)?)
+}
+
+/// Same as ``guild``, but without ``member_count``
+///
+/// Once a guild is stored, its member count is maintained by member events and must not be
+/// overwritten by whatever the cache happens to hold
+pub fn guild_info(cache_http: &CacheHttpImpl, guild_id: GuildId) -> Result<Bson, Error> {
+    let mut document = into_document(guild(cache_http, guild_id)?)?;
+
+    document.remove("member_count");
+
+    Ok(Bson::Document(document))
+}
+
+/// Whether @everyone can view a channel, only those channels are shown publicly
+fn everyone_can_view(g: &Guild, channel: &GuildChannel) -> bool {
+    // The @everyone role shares its id with the guild
+    let everyone = RoleId::new(g.id.get());
+
+    let mut permissions = match g.roles.get(&everyone) {
+        Some(role) => role.permissions,
+        None => return false,
+    };
+
+    if permissions.administrator() {
+        return true;
+    }
+
+    for overwrite in channel.permission_overwrites.iter() {
+        if matches!(overwrite.kind, PermissionOverwriteType::Role(id) if id == everyone) {
+            permissions = (permissions & !overwrite.deny) | overwrite.allow;
+        }
+    }
+
+    permissions.view_channel()
+}
+
+pub fn channel(cache_http: &CacheHttpImpl, channel: &GuildChannel) -> Result<Bson, Error> {
+    let g = channel
+        .guild_id
+        .to_guild_cached(&cache_http.cache)
+        .ok_or_else(|| {
+            error!("Guild not found in cache: gid={}", channel.guild_id);
+            "Guild not found in cache"
+        })?;
+
+    // Resolve the category from the parent channel, if any
+    let (category_name, category_id) = match channel.parent_id {
+        Some(parent_id) => {
+            let category_name = g
+                .channels
+                .get(&parent_id)
+                .map(|c| c.name.clone())
+                .unwrap_or_default();
+
+            (category_name, parent_id.to_string())
+        }
+        None => (String::new(), String::new()),
+    };
+
+    Ok(bson::to_bson(&crate::models::Channels {
+        id: channel.id.to_string(),
+        guild_id: channel.guild_id.to_string(),
+        name: channel.name.clone(),
+        channel_type: channel.kind,
+        category_name,
+        category_id,
+        position: i64::from(channel.position),
+        public: everyone_can_view(&g, channel),
     })?)
 }
 
+/// Builds the documents of every cached channel of a guild
+pub fn channel_documents(
+    cache_http: &CacheHttpImpl,
+    guild_id: GuildId,
+) -> Result<Vec<Bson>, Error> {
+    // The guild must not be held while building channels
+    let channels = guild_id
+        .to_guild_cached(&cache_http.cache)
+        .ok_or("Failed to get guild")?
+        .channels
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut docs = vec![];
+
+    for c in channels.iter() {
+        match channel(cache_http, c) {
+            Ok(bson) => docs.push(bson),
+            Err(e) => error!("Failed to create bson document for channel: {}", e),
+        }
+    }
+
+    Ok(docs)
+}
+
 /// Helper method to either add or update a document in a collection
 ///
+/// Writes are skipped if the mirror shows the document is unchanged since the last write
+///
 /// The bool returned is true if the document was added, false if it was updated
 pub async fn add_or_update(
     col: &Collection<Document>,
     filter: Document,
     bson: Bson,
 ) -> Result<bool, Error> {
-    // Check for user in mongo
-    let check = col.find_one(filter.clone(), None).await?;
+    let document = into_document(bson)?;
+
+    if MIRROR.unchanged(col.name(), &filter, &document) {
+        return Ok(false);
+    }
+
+    let res = col
+        .update_one(
+            filter.clone(),
+            doc! {"$set": document.clone()},
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
 
-    if check.is_none() {
+    MIRROR.record(col.name(), &filter, document);
+
+    if res.upserted_id.is_some() {
         info!(
-            "Entity not found in mongo, creating new entry (col={})",
+            "Entity not found in mongo, created new entry (col={})",
             col.name()
         );
-        let document = bson.as_document().ok_or("Failed to convert to document")?;
-        col.insert_one(document, None).await?;
         Ok(true)
     } else {
-        info!(
-            "Entity found in mongo, updating entity (col={})",
-            col.name()
-        );
-        col.update_one(filter, doc! {"$set": bson}, None).await?;
+        info!("Entity found in mongo, updated entity (col={})", col.name());
         Ok(false)
     }
 }
+
+/// Takes a full snapshot of a guild from cache
+///
+/// This writes the server document and every cached precense, and replaces the channels, roles,
+/// voice states, emojis and stickers of the guild, so anything removed while we were away is dropped
+pub async fn snapshot(
+    cache_http: &CacheHttpImpl,
+    cols: &Collections,
+    presences: &PresenceQueue,
+    guild_id: GuildId,
+) -> Result<(), Error> {
+    add_or_update(
+        &cols.server,
+        doc! {"id": guild_id.to_string()},
+        guild(cache_http, guild_id)?,
+    )
+    .await?;
+
+    // Copy what we need out of the cache
+    let (precenses, roles, voice_states, emojis, stickers) = {
+        let g = guild_id
+            .to_guild_cached(&cache_http.cache)
+            .ok_or("Failed to get guild")?;
+
+        let mut precenses = vec![];
+
+        for (user_id, precense) in g.presences.iter() {
+            match user_precense(&g, precense).and_then(into_document) {
+                Ok(document) => precenses.push((*user_id, document)),
+                Err(e) => error!("Failed to create bson document for precense: {}", e),
+            }
+        }
+
+        let mut roles = vec![];
+
+        for r in g.roles.values() {
+            match role(r) {
+                Ok(bson) => roles.push(bson),
+                Err(e) => error!("Failed to create bson document for role: {}", e),
+            }
+        }
+
+        let mut voice_states = vec![];
+
+        for v in g.voice_states.values() {
+            if v.channel_id.is_none() {
+                continue;
+            }
+
+            match voice_state(guild_id, v) {
+                Ok(bson) => voice_states.push(bson),
+                Err(e) => error!("Failed to create bson document for voice state: {}", e),
+            }
+        }
+
+        (
+            precenses,
+            roles,
+            voice_states,
+            emojis(guild_id, g.emojis.values()),
+            stickers(guild_id, g.stickers.values()),
+        )
+    };
+
+    let channel_docs = channel_documents(cache_http, guild_id)?;
+
+    info!(
+        "Writing guild snapshot: gid={}, precenses={}, channels={}, roles={}, voice={}",
+        guild_id,
+        precenses.len(),
+        channel_docs.len(),
+        roles.len(),
+        voice_states.len()
+    );
+
+    // Users go through the queue, so a snapshot can't overtake a newer queued precense or removal
+    for (user_id, document) in precenses {
+        presences.push(guild_id, user_id, document);
+    }
+
+    bulk::replace_guild(
+        &cols.db,
+        &cols.channel,
+        guild_id,
+        into_documents(channel_docs)?,
+    )
+    .await?;
+    bulk::replace_guild(&cols.db, &cols.role, guild_id, into_documents(roles)?).await?;
+    bulk::replace_guild(
+        &cols.db,
+        &cols.voice,
+        guild_id,
+        into_documents(voice_states)?,
+    )
+    .await?;
+    bulk::replace_guild(&cols.db, &cols.emoji, guild_id, into_documents(emojis)?).await?;
+    bulk::replace_guild(&cols.db, &cols.sticker, guild_id, into_documents(stickers)?).await?;
+
+    Ok(())
+}
+
+/// Replaces the scheduled events of a guild with what discord has
+///
+/// Events are not cached and the interested count is only sent over http, so this fetches them
+pub async fn sync_events(
+    cache_http: &CacheHttpImpl,
+    cols: &Collections,
+    guild_id: GuildId,
+) -> Result<(), Error> {
+    let events = guild_id.scheduled_events(&cache_http.http, true).await?;
+
+    let mut docs = vec![];
+
+    for e in events.iter() {
+        match scheduled_event(e) {
+            Ok(bson) => docs.push(bson),
+            Err(e) => error!("Failed to create bson document for event: {}", e),
+        }
+    }
+
+    bulk::replace_guild(&cols.db, &cols.event, guild_id, into_documents(docs)?).await
+}
+
+/// Builds the documents of all emojis of a guild
+pub fn emojis<'a>(guild_id: GuildId, emojis: impl Iterator<Item = &'a Emoji>) -> Vec<Bson> {
+    let mut docs = vec![];
+
+    for e in emojis {
+        match emoji(guild_id, e) {
+            Ok(bson) => docs.push(bson),
+            Err(e) => error!("Failed to create bson document for emoji: {}", e),
+        }
+    }
+
+    docs
+}
+
+/// Builds the documents of all stickers of a guild
+pub fn stickers<'a>(guild_id: GuildId, stickers: impl Iterator<Item = &'a Sticker>) -> Vec<Bson> {
+    let mut docs = vec![];
+
+    for st in stickers {
+        match sticker(guild_id, st) {
+            Ok(bson) => docs.push(bson),
+            Err(e) => error!("Failed to create bson document for sticker: {}", e),
+        }
+    }
+
+    docs
+}
+
+/// Unwraps a builder output into a document
+pub fn into_document(bson: Bson) -> Result<Document, Error> {
+    match bson {
+        Bson::Document(document) => Ok(document),
+        _ => Err("Failed to convert to document".into()),
+    }
+}
+
+/// Unwraps a list of builder outputs into documents
+pub fn into_documents(docs: Vec<Bson>) -> Result<Vec<Document>, Error> {
+    docs.into_iter().map(into_document).collect()
+}
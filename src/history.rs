@@ -0,0 +1,233 @@
+/// Presence history for activity charts
+///
+/// Two time-series collections are kept, both expiring after ``presence_history_retention_days``:
+///
+/// - ``bot__server_presence_history`` -> Every status transition of a member
+/// - ``bot__server_presence_hourly`` -> Status counters of every guild, sampled hourly
+use std::time::Duration;
+
+use futures_util::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::{CreateCollectionOptions, TimeseriesGranularity, TimeseriesOptions},
+    Database,
+};
+
+use crate::{config, gis, Error};
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+fn retention() -> Duration {
+    Duration::from_secs(config::CONFIG.presence_history_retention_days * 24 * 60 * 60)
+}
+
+/// Creates the time-series collections if needed and applies the configured retention
+pub async fn ensure_collections(cols: &gis::Collections) -> Result<(), Error> {
+    let existing = cols.db.list_collection_names(None).await?;
+
+    for (col, granularity) in [
+        (&cols.presence_history, TimeseriesGranularity::Seconds),
+        (&cols.presence_hourly, TimeseriesGranularity::Hours),
+    ] {
+        if existing.iter().any(|name| name == col.name()) {
+            cols.db
+                .run_command(
+                    doc! {
+                        "collMod": col.name(),
+                        "expireAfterSeconds": retention().as_secs() as i64,
+                    },
+                    None,
+                )
+                .await?;
+            continue;
+        }
+
+        info!("Creating time-series collection: {}", col.name());
+
+        cols.db
+            .create_collection(
+                col.name(),
+                CreateCollectionOptions::builder()
+                    .timeseries(
+                        TimeseriesOptions::builder()
+                            .time_field("ts".to_string())
+                            .meta_field(Some("meta".to_string()))
+                            .granularity(Some(granularity))
+                            .build(),
+                    )
+                    .expire_after_seconds(retention())
+                    .build(),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Builds a status transition of a member, ``from`` is unset if the member was not known before
+pub fn transition(guild_id: &str, user_id: &str, from: Option<&str>, to: Option<&str>) -> Document {
+    doc! {
+        "ts": DateTime::now(),
+        "meta": {"guild_id": guild_id, "id": user_id},
+        "from": from,
+        "to": to,
+    }
+}
+
+/// Stores status transitions
+pub async fn record(cols: &gis::Collections, transitions: Vec<Document>) -> Result<(), Error> {
+    if !transitions.is_empty() {
+        cols.presence_history.insert_many(transitions, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Reads an integer field, counters may be stored as either int32 or int64
+fn get_int(document: &Document, key: &str) -> Option<i64> {
+    match document.get(key) {
+        Some(Bson::Int32(n)) => Some(*n as i64),
+        Some(Bson::Int64(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Samples the status counters of every guild into the hourly collection
+pub async fn sample_hourly(cols: &gis::Collections) -> Result<(), Error> {
+    let now = DateTime::now().timestamp_millis();
+    let ts = DateTime::from_millis(now - now.rem_euclid(HOUR_MILLIS));
+
+    let mut cursor = cols.server.find(doc! {"deleted_at": null}, None).await?;
+
+    let mut samples = vec![];
+
+    while let Some(server) = cursor.try_next().await? {
+        let counts = server.get_document("status_counts").ok();
+        let count = |status: &str| counts.and_then(|c| get_int(c, status)).unwrap_or(0);
+
+        samples.push(doc! {
+            "ts": ts,
+            "meta": {"guild_id": server.get_str("id")?},
+            "online": count("online"),
+            "idle": count("idle"),
+            "dnd": count("dnd"),
+        });
+    }
+
+    info!("Sampled hourly presence counts: {} guilds", samples.len());
+
+    if !samples.is_empty() {
+        cols.presence_hourly.insert_many(samples, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Highest number of members not offline since ``since``, along with when that was
+pub async fn peak_online(
+    cols: &gis::Collections,
+    guild_id: &str,
+    since: DateTime,
+) -> Result<Option<(DateTime, i64)>, Error> {
+    let mut cursor = cols
+        .presence_hourly
+        .aggregate(
+            vec![
+                doc! {"$match": {"meta.guild_id": guild_id, "ts": {"$gte": since}}},
+                doc! {"$project": {"ts": 1, "total": {"$add": ["$online", "$idle", "$dnd"]}}},
+                doc! {"$sort": {"total": -1}},
+                doc! {"$limit": 1},
+            ],
+            None,
+        )
+        .await?;
+
+    match cursor.try_next().await? {
+        Some(peak) => Ok(Some((
+            *peak.get_datetime("ts")?,
+            get_int(&peak, "total").ok_or("Peak without a total")?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Hourly samples of a guild since ``since``, oldest first
+pub async fn online_series(
+    cols: &gis::Collections,
+    guild_id: &str,
+    since: DateTime,
+) -> Result<Vec<Document>, Error> {
+    let cursor = cols
+        .presence_hourly
+        .aggregate(
+            vec![
+                doc! {"$match": {"meta.guild_id": guild_id, "ts": {"$gte": since}}},
+                doc! {"$sort": {"ts": 1}},
+                doc! {"$project": {"_id": 0, "ts": 1, "online": 1, "idle": 1, "dnd": 1}},
+            ],
+            None,
+        )
+        .await?;
+
+    Ok(cursor.try_collect().await?)
+}
+
+/// Number of distinct members that came online per day of week and hour of day (UTC)
+///
+/// Indexed as ``[day][hour]`` with day 0 being sunday
+pub async fn heatmap(
+    cols: &gis::Collections,
+    guild_id: &str,
+    since: DateTime,
+) -> Result<[[i64; 24]; 7], Error> {
+    let mut cursor = cols
+        .presence_history
+        .aggregate(
+            vec![
+                doc! {"$match": {
+                    "meta.guild_id": guild_id,
+                    "ts": {"$gte": since},
+                    "to": {"$in": ["online", "idle", "dnd"]},
+                }},
+                doc! {"$group": {
+                    "_id": {"day": {"$dayOfWeek": "$ts"}, "hour": {"$hour": "$ts"}},
+                    "members": {"$addToSet": "$meta.id"},
+                }},
+                doc! {"$project": {"count": {"$size": "$members"}}},
+            ],
+            None,
+        )
+        .await?;
+
+    let mut heatmap = [[0; 24]; 7];
+
+    while let Some(cell) = cursor.try_next().await? {
+        let id = cell.get_document("_id")?;
+        // $dayOfWeek is 1 (sunday) to 7 (saturday)
+        let day = (id.get_i32("day")? - 1) as usize;
+        let hour = id.get_i32("hour")? as usize;
+
+        if day < 7 && hour < 24 {
+            heatmap[day][hour] = cell.get_i32("count")? as i64;
+        }
+    }
+
+    Ok(heatmap)
+}
+
+pub async fn run(db: Database) {
+    let cols = gis::Collections::new(&db);
+
+    loop {
+        // Sample at the top of every hour
+        let now = DateTime::now().timestamp_millis();
+        let wait = HOUR_MILLIS - now.rem_euclid(HOUR_MILLIS);
+
+        tokio::time::sleep(Duration::from_millis(wait as u64)).await;
+
+        if let Err(e) = sample_hourly(&cols).await {
+            error!("Failed to sample hourly presence counts: {}", e);
+        }
+    }
+}
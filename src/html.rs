@@ -0,0 +1,441 @@
+/// Embeddable HTML widget, meant to be shown in an iframe
+///
+/// The page has no script and no inline styles, the stylesheet is served separately so the page
+/// works under a strict content security policy
+use std::{collections::HashMap, fmt::Write};
+
+use poise::serenity_prelude::ChannelType;
+use strum_macros::{Display, EnumString};
+
+use crate::{models, svg::escape, widget::Widget};
+
+/// Policy sent with the widget page, images are only loaded from the discord CDN
+pub const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'self'; img-src https://cdn.discordapp.com; base-uri 'none'; form-action 'none'; frame-ancestors *";
+
+#[derive(Debug, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Theme {
+    Light,
+    Dark,
+    /// Follows the color scheme of the visitor
+    Auto,
+}
+
+/// Default accent, discord blurple
+pub const DEFAULT_ACCENT: &str = "5865f2";
+
+/// Validates a hex color without the leading ``#``
+pub fn parse_accent(accent: &str) -> Option<String> {
+    let valid = matches!(accent.len(), 3 | 6) && accent.chars().all(|c| c.is_ascii_hexdigit());
+
+    valid.then(|| accent.to_ascii_lowercase())
+}
+
+pub struct Strings {
+    pub online: &'static str,
+    pub idle: &'static str,
+    pub dnd: &'static str,
+    /// ``{}`` is replaced by the number of members online
+    pub online_count: &'static str,
+    /// ``{}`` is replaced by the number of members not listed
+    pub more: &'static str,
+    pub channels: &'static str,
+    pub join: &'static str,
+}
+
+const EN: Strings = Strings {
+    online: "Online",
+    idle: "Idle",
+    dnd: "Do Not Disturb",
+    online_count: "{} Online",
+    more: "and {} more",
+    channels: "Channels",
+    join: "Join Server",
+};
+
+/// Translations by locale, as used by discord
+static LOCALES: [(&str, Strings); 7] = [
+    ("en-US", EN),
+    (
+        "de",
+        Strings {
+            online: "Online",
+            idle: "Abwesend",
+            dnd: "Bitte nicht stören",
+            online_count: "{} online",
+            more: "und {} weitere",
+            channels: "Kanäle",
+            join: "Server beitreten",
+        },
+    ),
+    (
+        "fr",
+        Strings {
+            online: "En ligne",
+            idle: "Inactif",
+            dnd: "Ne pas déranger",
+            online_count: "{} en ligne",
+            more: "et {} de plus",
+            channels: "Salons",
+            join: "Rejoindre le serveur",
+        },
+    ),
+    (
+        "es-ES",
+        Strings {
+            online: "En línea",
+            idle: "Ausente",
+            dnd: "No molestar",
+            online_count: "{} en línea",
+            more: "y {} más",
+            channels: "Canales",
+            join: "Unirse al servidor",
+        },
+    ),
+    (
+        "pt-BR",
+        Strings {
+            online: "Disponível",
+            idle: "Ausente",
+            dnd: "Não perturbar",
+            online_count: "{} online",
+            more: "e mais {}",
+            channels: "Canais",
+            join: "Entrar no servidor",
+        },
+    ),
+    (
+        "nl",
+        Strings {
+            online: "Online",
+            idle: "Inactief",
+            dnd: "Niet storen",
+            online_count: "{} online",
+            more: "en {} meer",
+            channels: "Kanalen",
+            join: "Server joinen",
+        },
+    ),
+    (
+        "it",
+        Strings {
+            online: "Online",
+            idle: "Inattivo",
+            dnd: "Non disturbare",
+            online_count: "{} online",
+            more: "e altri {}",
+            channels: "Canali",
+            join: "Unisciti al server",
+        },
+    ),
+];
+
+fn language(locale: &str) -> &str {
+    locale.split('-').next().unwrap_or(locale)
+}
+
+/// Finds the strings of a locale, falling back to the language and then to english
+pub fn strings(locale: &str) -> &'static (&'static str, Strings) {
+    LOCALES
+        .iter()
+        .find(|(l, _)| l.eq_ignore_ascii_case(locale))
+        .or_else(|| {
+            LOCALES
+                .iter()
+                .find(|(l, _)| language(l).eq_ignore_ascii_case(language(locale)))
+        })
+        .unwrap_or(&LOCALES[0])
+}
+
+pub struct Options {
+    pub theme: Theme,
+    /// Hex color without the leading ``#``
+    pub accent: String,
+    pub locale: String,
+}
+
+/// Stylesheet of the widget, the accent is passed along as it can't be set inline
+pub fn stylesheet(accent: &str) -> String {
+    format!(":root {{ --accent: #{}; }}\n{}", accent, STYLESHEET)
+}
+
+const STYLESHEET: &str = r#"
+.theme-dark, .theme-auto {
+    --bg: #2b2d31;
+    --card: #1e1f22;
+    --fg: #f2f3f5;
+    --muted: #b5bac1;
+}
+.theme-light {
+    --bg: #ffffff;
+    --card: #f2f3f5;
+    --fg: #060607;
+    --muted: #4e5058;
+}
+@media (prefers-color-scheme: light) {
+    .theme-auto {
+        --bg: #ffffff;
+        --card: #f2f3f5;
+        --fg: #060607;
+        --muted: #4e5058;
+    }
+}
+* { box-sizing: border-box; }
+html, body { margin: 0; height: 100%; }
+body {
+    background: var(--bg);
+    color: var(--fg);
+    font: 14px/1.4 "gg sans", "Noto Sans", Helvetica, Arial, sans-serif;
+    display: flex;
+    flex-direction: column;
+}
+header {
+    display: flex;
+    align-items: center;
+    gap: 12px;
+    padding: 12px 16px;
+    background: var(--accent);
+    color: #ffffff;
+}
+header img { width: 40px; height: 40px; border-radius: 50%; }
+header h1 { margin: 0; font-size: 16px; }
+header p { margin: 0; font-size: 13px; opacity: 0.9; }
+main { flex: 1; overflow-y: auto; padding: 8px 16px; }
+h2, h3 {
+    margin: 16px 0 4px;
+    font-size: 12px;
+    text-transform: uppercase;
+    color: var(--muted);
+}
+h3 { margin-top: 8px; font-size: 11px; }
+ul { list-style: none; margin: 0; padding: 0; }
+li { display: flex; align-items: center; gap: 8px; padding: 3px 0; }
+.avatar { position: relative; width: 24px; height: 24px; flex: none; }
+.avatar img { width: 24px; height: 24px; border-radius: 50%; }
+.avatar span {
+    position: absolute;
+    right: -2px;
+    bottom: -2px;
+    width: 10px;
+    height: 10px;
+    border-radius: 50%;
+    border: 2px solid var(--bg);
+}
+.status-online { background: #23a55a; }
+.status-idle { background: #f0b232; }
+.status-dnd { background: #f23f43; }
+.activity { color: var(--muted); font-size: 12px; margin-left: auto; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }
+.channel { color: var(--muted); }
+.channel .count { margin-left: auto; font-size: 12px; }
+.more { color: var(--muted); font-size: 12px; }
+footer { padding: 12px 16px; background: var(--card); }
+.join {
+    display: block;
+    padding: 8px;
+    border-radius: 4px;
+    background: var(--accent);
+    color: #ffffff;
+    text-align: center;
+    text-decoration: none;
+    font-weight: bold;
+}
+"#;
+
+fn status_section(html: &mut String, title: &str, status: &str, members: &[&models::User]) {
+    if members.is_empty() {
+        return;
+    }
+
+    let _ = write!(html, "<h2>{} — {}</h2><ul>", escape(title), members.len());
+
+    for member in members {
+        let _ = write!(
+            html,
+            r#"<li><span class="avatar"><img src="{}" alt="" loading="lazy"><span class="status-{}"></span></span><span>{}</span>"#,
+            escape(&member.avatar),
+            status,
+            escape(&member.name)
+        );
+
+        if let Some(activity) = member.activities.iter().find(|a| a.kind != "custom") {
+            let _ = write!(
+                html,
+                r#"<span class="activity">{}</span>"#,
+                escape(&activity.name)
+            );
+        }
+
+        html.push_str("</li>");
+    }
+
+    html.push_str("</ul>");
+}
+
+fn channel_icon(kind: ChannelType) -> &'static str {
+    match kind {
+        ChannelType::Voice | ChannelType::Stage => "🔊",
+        _ => "#",
+    }
+}
+
+/// Channels grouped under their category, channels without a listed category come first
+fn channel_sections(html: &mut String, widget: &Widget) {
+    let occupants = widget
+        .voice
+        .iter()
+        .fold(HashMap::<&str, usize>::new(), |mut occupants, v| {
+            *occupants.entry(v.channel_id.as_str()).or_default() += 1;
+            occupants
+        });
+
+    let mut categories = widget
+        .channels
+        .iter()
+        .filter(|c| c.channel_type == ChannelType::Category)
+        .collect::<Vec<_>>();
+    categories.sort_by_key(|c| c.position);
+
+    // A public channel can sit in a category that isn't public, it is listed without one then
+    let listed = |category_id: &str| categories.iter().any(|c| c.id == category_id);
+
+    let groups = std::iter::once(("", ""))
+        .chain(categories.iter().map(|c| (c.id.as_str(), c.name.as_str())));
+
+    for (category_id, category_name) in groups {
+        let mut channels = widget
+            .channels
+            .iter()
+            .filter(|c| {
+                let group = if listed(&c.category_id) {
+                    c.category_id.as_str()
+                } else {
+                    ""
+                };
+
+                c.channel_type != ChannelType::Category && group == category_id
+            })
+            .collect::<Vec<_>>();
+
+        if channels.is_empty() {
+            continue;
+        }
+
+        channels.sort_by_key(|c| (c.channel_type == ChannelType::Voice, c.position));
+
+        if !category_name.is_empty() {
+            let _ = write!(html, "<h3>{}</h3>", escape(category_name));
+        }
+
+        html.push_str("<ul>");
+
+        for channel in channels {
+            let _ = write!(
+                html,
+                r#"<li class="channel"><span>{}</span><span>{}</span>"#,
+                channel_icon(channel.channel_type),
+                escape(&channel.name)
+            );
+
+            if let Some(count) = occupants.get(channel.id.as_str()) {
+                let _ = write!(html, r#"<span class="count">{}</span>"#, count);
+            }
+
+            html.push_str("</li>");
+        }
+
+        html.push_str("</ul>");
+    }
+}
+
+/// Renders the widget page: online members grouped by status and channels grouped by category
+pub fn render(widget: &Widget, options: &Options) -> String {
+    let (locale, strings) = strings(&options.locale);
+
+    let mut html = String::new();
+
+    let _ = write!(
+        html,
+        r#"<!DOCTYPE html><html lang="{}" class="theme-{}"><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><meta name="referrer" content="no-referrer"><title>{}</title><link rel="stylesheet" href="../../widget.css?accent={}"></head><body>"#,
+        locale,
+        options.theme,
+        escape(&widget.server.name),
+        options.accent
+    );
+
+    let _ = write!(
+        html,
+        r#"<header><img src="{}" alt=""><div><h1>{}</h1><p>{}</p></div></header><main>"#,
+        escape(&widget.server.icon),
+        escape(&widget.server.name),
+        strings
+            .online_count
+            .replace("{}", &widget.presence_count.to_string())
+    );
+
+    for (title, status) in [
+        (strings.online, "online"),
+        (strings.idle, "idle"),
+        (strings.dnd, "dnd"),
+    ] {
+        let members = widget
+            .members
+            .iter()
+            .filter(|m| m.status == status)
+            .collect::<Vec<_>>();
+
+        status_section(&mut html, title, status, &members);
+    }
+
+    let hidden = widget.presence_count - widget.members.len() as i64;
+
+    if hidden > 0 {
+        let _ = write!(
+            html,
+            r#"<p class="more">{}</p>"#,
+            strings.more.replace("{}", &hidden.to_string())
+        );
+    }
+
+    if !widget.channels.is_empty() {
+        let _ = write!(html, "<h2>{}</h2>", escape(strings.channels));
+        channel_sections(&mut html, widget);
+    }
+
+    html.push_str("</main>");
+
+    if let Some(invite) = widget.instant_invite() {
+        let _ = write!(
+            html,
+            r#"<footer><a class="join" href="{}" target="_blank" rel="noopener noreferrer">{}</a></footer>"#,
+            escape(&invite),
+            escape(strings.join)
+        );
+    }
+
+    html.push_str("</body></html>");
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_accents() {
+        assert_eq!(parse_accent("FF00aa").as_deref(), Some("ff00aa"));
+        assert_eq!(parse_accent("abc").as_deref(), Some("abc"));
+        assert_eq!(parse_accent("abcd"), None);
+        assert_eq!(parse_accent("ggg"), None);
+        assert_eq!(parse_accent("#abc"), None);
+    }
+
+    #[test]
+    fn falls_back_to_language_then_english() {
+        assert_eq!(strings("de").0, "de");
+        assert_eq!(strings("de-AT").0, "de");
+        assert_eq!(strings("pt-br").0, "pt-BR");
+        assert_eq!(strings("es-419").0, "es-ES");
+        assert_eq!(strings("ja").0, "en-US");
+    }
+}
@@ -0,0 +1,128 @@
+/// iCalendar (RFC 5545) feeds of guild scheduled events
+use mongodb::bson::DateTime;
+
+use crate::models::Event;
+
+/// Formats a date as an iCalendar UTC date-time, e.g. ``20230401T180000Z``
+fn ics_date(date: DateTime) -> String {
+    // Drop milliseconds so the RFC 3339 string has no fractional seconds
+    let millis = date.timestamp_millis();
+    let date = DateTime::from_millis(millis - millis.rem_euclid(1000));
+
+    date.try_to_rfc3339_string()
+        .unwrap_or_default()
+        .replace(['-', ':'], "")
+}
+
+/// Escapes a TEXT value
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Writes a content line, folding it so no line is longer than 75 octets
+fn push_line(out: &mut String, line: &str) {
+    let mut len = 0;
+
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+
+        out.push(c);
+        len += c.len_utf8();
+    }
+
+    out.push_str("\r\n");
+}
+
+/// Builds a calendar containing the given events of a guild
+pub fn calendar(guild_name: &str, events: &[Event]) -> String {
+    let mut out = String::new();
+    let now = ics_date(DateTime::now());
+
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//DisWidgets//Scheduled Events//EN");
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    push_line(&mut out, &format!("X-WR-CALNAME:{}", escape(guild_name)));
+
+    for event in events {
+        push_line(&mut out, "BEGIN:VEVENT");
+        push_line(&mut out, &format!("UID:{}@diswidgets", event.id));
+        push_line(&mut out, &format!("DTSTAMP:{}", now));
+        push_line(&mut out, &format!("DTSTART:{}", ics_date(event.start_time)));
+
+        if let Some(end_time) = event.end_time {
+            push_line(&mut out, &format!("DTEND:{}", ics_date(end_time)));
+        }
+
+        push_line(&mut out, &format!("SUMMARY:{}", escape(&event.name)));
+
+        if let Some(description) = &event.description {
+            push_line(&mut out, &format!("DESCRIPTION:{}", escape(description)));
+        }
+
+        if let Some(location) = &event.location {
+            push_line(&mut out, &format!("LOCATION:{}", escape(location)));
+        }
+
+        push_line(
+            &mut out,
+            &format!(
+                "URL:https://discord.com/events/{}/{}",
+                event.guild_id, event.id
+            ),
+        );
+        push_line(
+            &mut out,
+            if event.status == "canceled" {
+                "STATUS:CANCELLED"
+            } else {
+                "STATUS:CONFIRMED"
+            },
+        );
+        push_line(&mut out, "END:VEVENT");
+    }
+
+    push_line(&mut out, "END:VCALENDAR");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(escape("a;b,c\\d\ne\r\nf"), "a\\;b\\,c\\\\d\\ne\\nf");
+    }
+
+    #[test]
+    fn folds_multibyte_lines_at_75_octets() {
+        let line = format!("SUMMARY:{}", "é".repeat(100));
+
+        let mut out = String::new();
+        push_line(&mut out, &line);
+
+        let lines = out.strip_suffix("\r\n").unwrap().split("\r\n");
+        let mut unfolded = String::new();
+
+        for (i, l) in lines.enumerate() {
+            assert!(l.len() <= 75, "line {} is {} octets", i, l.len());
+
+            if i == 0 {
+                unfolded.push_str(l);
+            } else {
+                unfolded.push_str(l.strip_prefix(' ').expect("continuation without a space"));
+            }
+        }
+
+        assert_eq!(unfolded, line);
+    }
+}
@@ -0,0 +1,150 @@
+/// Bounded in-memory cache evicting the least recently used entry
+///
+/// Entries optionally expire after a fixed time to live
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+struct Entry<V> {
+    value: V,
+    inserted: Instant,
+    /// Tick of the last access, the lowest one is evicted first
+    used: u64,
+}
+
+pub struct Lru<K, V> {
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: HashMap<K, Entry<V>>,
+    tick: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn expired(&self, entry: &Entry<V>) -> bool {
+        self.ttl
+            .map(|ttl| entry.inserted.elapsed() >= ttl)
+            .unwrap_or(false)
+    }
+
+    /// Returns a copy of the value of a key, unless it expired
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let expired = self.entries.get(key).map(|entry| self.expired(entry))?;
+
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        entry.used = self.tick;
+
+        Some(entry.value.clone())
+    }
+
+    /// Inserts a value, evicting the least recently used entry when full
+    pub fn insert(&mut self, key: K, value: V) {
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some(ttl) = self.ttl {
+                self.entries
+                    .retain(|_, entry| entry.inserted.elapsed() < ttl);
+            }
+
+            if self.entries.len() >= self.capacity {
+                if let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone())
+                {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+
+        self.tick += 1;
+
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                inserted: Instant::now(),
+                used: self.tick,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2, None);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        lru.insert("c", 3);
+
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.get(&"a"), None);
+        assert_eq!(lru.get(&"b"), Some(2));
+        assert_eq!(lru.get(&"c"), Some(3));
+    }
+
+    #[test]
+    fn get_refreshes_entry() {
+        let mut lru = Lru::new(2, None);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+
+        assert_eq!(lru.get(&"a"), Some(1));
+        lru.insert("c", 3);
+
+        assert_eq!(lru.get(&"a"), Some(1));
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"c"), Some(3));
+    }
+
+    #[test]
+    fn replacing_a_key_does_not_evict() {
+        let mut lru = Lru::new(2, None);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        lru.insert("a", 3);
+
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.get(&"a"), Some(3));
+        assert_eq!(lru.get(&"b"), Some(2));
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let mut lru = Lru::new(2, Some(Duration::ZERO));
+        lru.insert("a", 1);
+
+        assert_eq!(lru.get(&"a"), None);
+        assert_eq!(lru.len(), 0);
+
+        let mut lru = Lru::new(2, Some(Duration::from_secs(60)));
+        lru.insert("a", 1);
+
+        assert_eq!(lru.get(&"a"), Some(1));
+    }
+}
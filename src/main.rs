@@ -1,20 +1,39 @@
+use std::sync::Arc;
+
 use log::{error, info};
-use poise::serenity_prelude::FullEvent;
+use poise::serenity_prelude::{ChannelType, FullEvent};
 
 use crate::cache::CacheHttpImpl;
 
 use mongodb::{
-    bson::{doc, Document},
+    bson::{doc, Bson, DateTime},
     options::ClientOptions,
     Client,
 };
 
+mod api;
+mod bulk;
 mod cache;
+mod chunks;
+mod cleanup;
 mod config;
+mod counts;
 mod gis;
 mod help;
+mod history;
+mod html;
+mod ics;
+mod lru;
 mod models;
+mod png;
+mod query;
+mod reconcile;
+mod rollup;
+mod state;
 mod stats;
+mod svg;
+mod widget;
+mod writeback;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
@@ -23,6 +42,9 @@ type Context<'a> = poise::Context<'a, Data, Error>;
 pub struct Data {
     cache_http: cache::CacheHttpImpl,
     mongo: Client,
+    presences: Arc<writeback::PresenceQueue>,
+    daily_stats: Arc<rollup::DailyStats>,
+    chunks: chunks::ChunkTracker,
 }
 
 #[poise::command(prefix_command)]
@@ -75,9 +97,7 @@ async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
 
 async fn event_listener(event: &FullEvent, user_data: &Data) -> Result<(), Error> {
     let db: mongodb::Database = user_data.mongo.database("diswidgets");
-    let scol = db.collection::<Document>("bot__server_info");
-    let ucol = db.collection::<Document>("bot__server_user");
-    let ccol = db.collection::<Document>("bot__server_channel");
+    let cols = gis::Collections::new(&db);
 
     match event {
         FullEvent::InteractionCreate {
@@ -86,13 +106,158 @@ async fn event_listener(event: &FullEvent, user_data: &Data) -> Result<(), Error
         } => {
             info!("Interaction received: {:?}", interaction.id());
         }
-        FullEvent::Ready {
-            data_about_bot,
-            ctx: _,
-        } => {
+        FullEvent::Ready { data_about_bot, .. } => {
             info!("{} is ready!", data_about_bot.user.name);
+
+            // Precenses may have changed while we were away, they are confirmed by the GuildCreate
+            // that follows for every guild, which is also where guilds are snapshotted
+            for guild in data_about_bot.guilds.iter() {
+                if let Err(e) = counts::mark_stale(&cols, &user_data.presences, guild.id).await {
+                    error!("Failed to mark guild stale: gid={}, err={}", guild.id, e);
+                }
+            }
+        }
+        FullEvent::Resume { .. } => {
+            // Discord replays every missed event on a resume, so nothing can have gone stale
+            info!("Resumed session");
+        }
+        FullEvent::GuildCreate { guild, ctx, .. } => {
+            info!("Snapshotting guild: gid={}", guild.id);
+
+            gis::snapshot(&user_data.cache_http, &cols, &user_data.presences, guild.id).await?;
+
+            // The snapshot only covers what discord sent, backfill the rest of large guilds
+            if guild.large {
+                user_data.chunks.request(ctx, guild.id);
+            } else {
+                // Everyone online was part of the GuildCreate
+                user_data.presences.expire_stale(&cols, guild.id).await;
+            }
+
+            gis::sync_events(&user_data.cache_http, &cols, guild.id).await?;
+        }
+        FullEvent::GuildMembersChunk { chunk, .. } => {
+            user_data
+                .chunks
+                .handle(&user_data.cache_http, &cols, &user_data.presences, chunk)
+                .await?;
+        }
+        FullEvent::GuildUpdate { new_data, .. } => {
+            info!("Updating guild: gid={}", new_data.id);
+            gis::add_or_update(
+                &cols.server,
+                doc! {"id": new_data.id.to_string()},
+                gis::guild_info(&user_data.cache_http, new_data.id)?,
+            )
+            .await?;
         }
-        FullEvent::PresenceUpdate { ctx, new_data } => {
+        FullEvent::GuildDelete { incomplete, .. } => {
+            if incomplete.unavailable {
+                info!(
+                    "Guild became unavailable, keeping it: gid={}",
+                    incomplete.id
+                );
+                return Ok(());
+            }
+
+            info!("Removed from guild, tombstoning it: gid={}", incomplete.id);
+
+            let filter = doc! {"id": incomplete.id.to_string()};
+
+            cols.server
+                .update_one(
+                    filter.clone(),
+                    doc! {"$set": {"deleted_at": DateTime::now()}},
+                    None,
+                )
+                .await?;
+            state::MIRROR.forget(cols.server.name(), &filter);
+        }
+        FullEvent::GuildMemberAddition { new_member, .. } => {
+            info!(
+                "Member joined: gid={}, uid={}",
+                new_member.guild_id, new_member.user.id
+            );
+
+            user_data.daily_stats.member_joined(new_member.guild_id);
+            counts::add_members(&cols, &new_member.guild_id.to_string(), 1).await?;
+        }
+        FullEvent::GuildMemberRemoval { guild_id, user, .. } => {
+            info!("Removing member: gid={}, uid={}", guild_id, user.id);
+
+            user_data.daily_stats.member_left(*guild_id);
+            counts::add_members(&cols, &guild_id.to_string(), -1).await?;
+
+            user_data.presences.remove(*guild_id, user.id);
+        }
+        FullEvent::ChannelCreate { channel, .. } => {
+            info!(
+                "Adding new channel: gid={}, cid={}",
+                channel.guild_id, channel.id
+            );
+            gis::add_or_update(
+                &cols.channel,
+                doc! {"id": channel.id.to_string(), "guild_id": channel.guild_id.to_string()},
+                gis::channel(&user_data.cache_http, channel)?,
+            )
+            .await?;
+        }
+        FullEvent::ChannelUpdate { new, .. } => {
+            let channel = match new.clone().guild() {
+                Some(channel) => channel,
+                None => return Ok(()),
+            };
+
+            info!(
+                "Updating channel: gid={}, cid={}",
+                channel.guild_id, channel.id
+            );
+            gis::add_or_update(
+                &cols.channel,
+                doc! {"id": channel.id.to_string(), "guild_id": channel.guild_id.to_string()},
+                gis::channel(&user_data.cache_http, &channel)?,
+            )
+            .await?;
+
+            // Category names are stored on every child channel
+            if channel.kind == ChannelType::Category {
+                let category_id = channel.id.to_string();
+
+                cols.channel
+                    .update_many(
+                        doc! {"category_id": &category_id},
+                        doc! {"$set": {"category_name": &channel.name}},
+                        None,
+                    )
+                    .await?;
+                state::MIRROR.set_where(
+                    cols.channel.name(),
+                    "category_id",
+                    &category_id,
+                    "category_name",
+                    Bson::String(channel.name.clone()),
+                );
+            }
+        }
+        FullEvent::ChannelDelete { channel, .. } => {
+            info!(
+                "Removing channel: gid={}, cid={}",
+                channel.guild_id, channel.id
+            );
+            let filter =
+                doc! {"id": channel.id.to_string(), "guild_id": channel.guild_id.to_string()};
+
+            cols.channel.delete_one(filter.clone(), None).await?;
+            state::MIRROR.forget(cols.channel.name(), &filter);
+        }
+        FullEvent::Message { new_message, .. } => {
+            if let Some(guild_id) = new_message.guild_id {
+                user_data
+                    .daily_stats
+                    .message(guild_id, new_message.channel_id);
+            }
+        }
+        FullEvent::PresenceUpdate { new_data, .. } => {
             let guild_id = match new_data.guild_id {
                 Some(guild_id) => guild_id,
                 None => {
@@ -101,57 +266,209 @@ async fn event_listener(event: &FullEvent, user_data: &Data) -> Result<(), Error
                 }
             };
 
-            let inserted = gis::add_or_update(
-                &scol,
+            gis::add_or_update(
+                &cols.server,
                 doc! {"id": guild_id.to_string()},
-                gis::guild(&user_data.cache_http, guild_id)?,
+                gis::guild_info(&user_data.cache_http, guild_id)?,
             )
             .await?;
 
-            if inserted {
-                info!(
-                    "Inserted new guild, adding current precenses: {}",
-                    guild_id.to_string()
-                );
-                // Get all precenses in guild
-                let adds = {
-                    let guild = guild_id
-                        .to_guild_cached(&ctx)
-                        .ok_or("Failed to get guild")?;
-
-                    let mut adds = vec![];
-
-                    for (_, precense) in guild.presences.iter() {
-                        match gis::user_precense(guild_id, precense) {
-                            Ok(bson) => adds.push(bson),
-                            Err(e) => error!("Failed to create bson document for precense: {}", e),
-                        }
-                    }
-
-                    adds
-                };
+            let document = {
+                let g = guild_id
+                    .to_guild_cached(&user_data.cache_http.cache)
+                    .ok_or("Failed to get guild")?;
 
-                // Add all precenses to mongo
-                for add in adds {
-                    gis::add_or_update(
-                        &ucol,
-                        doc! {"id": &new_data.user.id.to_string(), "guild_id": &guild_id.to_string()},
-                        add
-                    ).await?;
-                }
+                gis::into_document(gis::user_precense(&g, new_data)?)?
+            };
+
+            user_data
+                .presences
+                .push(guild_id, new_data.user.id, document);
+        }
+        FullEvent::GuildMemberUpdate { new, .. } => {
+            // Goes through the queue, a direct write could be overwritten by a queued precense
+            let document = {
+                let g = new
+                    .guild_id
+                    .to_guild_cached(&user_data.cache_http.cache)
+                    .ok_or("Failed to get guild")?;
+
+                gis::member_document(&cols, &g, new.user.id)?
+            };
+
+            if let Some(document) = document {
+                user_data
+                    .presences
+                    .push(new.guild_id, new.user.id, document);
+            }
+        }
+        FullEvent::VoiceStateUpdate { new, .. } => {
+            let guild_id = match new.guild_id {
+                Some(guild_id) => guild_id,
+                None => return Ok(()),
+            };
+
+            let filter = doc! {"id": new.user_id.to_string(), "guild_id": guild_id.to_string()};
+
+            if new.channel_id.is_some() {
+                gis::add_or_update(&cols.voice, filter, gis::voice_state(guild_id, new)?).await?;
             } else {
-                info!(
-                    "Adding new precense: gid={}, uid={}",
-                    guild_id.to_string(),
-                    new_data.user.id.to_string()
-                );
-                gis::add_or_update(
-                    &ucol,
-                    doc! {"id": &new_data.user.id.to_string(), "guild_id":  &guild_id.to_string()},
-                    gis::user_precense(guild_id, new_data)?,
+                info!("Member left voice: gid={}, uid={}", guild_id, new.user_id);
+
+                cols.voice.delete_one(filter.clone(), None).await?;
+                state::MIRROR.forget(cols.voice.name(), &filter);
+            }
+        }
+        FullEvent::GuildEmojisUpdate {
+            guild_id,
+            current_state,
+            ..
+        } => {
+            info!(
+                "Syncing emojis: gid={}, count={}",
+                guild_id,
+                current_state.len()
+            );
+
+            bulk::replace_guild(
+                &cols.db,
+                &cols.emoji,
+                *guild_id,
+                gis::into_documents(gis::emojis(*guild_id, current_state.values()))?,
+            )
+            .await?;
+        }
+        FullEvent::GuildStickersUpdate {
+            guild_id,
+            current_state,
+            ..
+        } => {
+            info!(
+                "Syncing stickers: gid={}, count={}",
+                guild_id,
+                current_state.len()
+            );
+
+            bulk::replace_guild(
+                &cols.db,
+                &cols.sticker,
+                *guild_id,
+                gis::into_documents(gis::stickers(*guild_id, current_state.values()))?,
+            )
+            .await?;
+        }
+        FullEvent::GuildScheduledEventCreate { event, .. }
+        | FullEvent::GuildScheduledEventUpdate { event, .. } => {
+            info!("Updating event: gid={}, eid={}", event.guild_id, event.id);
+            gis::add_or_update(
+                &cols.event,
+                doc! {"id": event.id.to_string(), "guild_id": event.guild_id.to_string()},
+                gis::scheduled_event(event)?,
+            )
+            .await?;
+        }
+        FullEvent::GuildScheduledEventDelete { event, .. } => {
+            info!("Removing event: gid={}, eid={}", event.guild_id, event.id);
+
+            let filter = doc! {"id": event.id.to_string(), "guild_id": event.guild_id.to_string()};
+
+            cols.event.delete_one(filter.clone(), None).await?;
+            state::MIRROR.forget(cols.event.name(), &filter);
+        }
+        FullEvent::GuildScheduledEventUserAdd { subscribed, .. } => {
+            scheduled_event_interest(
+                &cols,
+                &subscribed.scheduled_event_id.to_string(),
+                &subscribed.guild_id.to_string(),
+                1,
+            )
+            .await?;
+        }
+        FullEvent::GuildScheduledEventUserRemove { unsubscribed, .. } => {
+            scheduled_event_interest(
+                &cols,
+                &unsubscribed.scheduled_event_id.to_string(),
+                &unsubscribed.guild_id.to_string(),
+                -1,
+            )
+            .await?;
+        }
+        FullEvent::GuildRoleCreate { new, .. } => {
+            info!("Adding new role: gid={}, rid={}", new.guild_id, new.id);
+            gis::add_or_update(
+                &cols.role,
+                doc! {"id": new.id.to_string(), "guild_id": new.guild_id.to_string()},
+                gis::role(new)?,
+            )
+            .await?;
+        }
+        FullEvent::GuildRoleUpdate {
+            old_data_if_available,
+            new,
+            ..
+        } => {
+            info!("Updating role: gid={}, rid={}", new.guild_id, new.id);
+            gis::add_or_update(
+                &cols.role,
+                doc! {"id": new.id.to_string(), "guild_id": new.guild_id.to_string()},
+                gis::role(new)?,
+            )
+            .await?;
+
+            // The permissions of @everyone decide which channels are public
+            let everyone_changed = new.id.get() == new.guild_id.get()
+                && match old_data_if_available {
+                    Some(old) => old.permissions != new.permissions,
+                    None => true,
+                };
+
+            if everyone_changed {
+                bulk::replace_guild(
+                    &cols.db,
+                    &cols.channel,
+                    new.guild_id,
+                    gis::into_documents(gis::channel_documents(
+                        &user_data.cache_http,
+                        new.guild_id,
+                    )?)?,
                 )
                 .await?;
             }
+
+            // Only hoisting or moving a role can change the hoisted role of its members
+            let hoist_changed = match old_data_if_available {
+                Some(old) => old.hoist != new.hoist || old.position != new.position,
+                None => true,
+            };
+
+            if hoist_changed {
+                let g = new
+                    .guild_id
+                    .to_guild_cached(&user_data.cache_http.cache)
+                    .ok_or("Failed to get guild")?;
+
+                let user_ids = gis::role_holders(&cols, &g, new.id);
+                gis::refresh_members(&cols, &user_data.presences, &g, user_ids);
+            }
+        }
+        FullEvent::GuildRoleDelete {
+            guild_id,
+            removed_role_id,
+            ..
+        } => {
+            info!("Removing role: gid={}, rid={}", guild_id, removed_role_id);
+
+            let filter = doc! {"id": removed_role_id.to_string(), "guild_id": guild_id.to_string()};
+
+            cols.role.delete_one(filter.clone(), None).await?;
+            state::MIRROR.forget(cols.role.name(), &filter);
+
+            let g = guild_id
+                .to_guild_cached(&user_data.cache_http.cache)
+                .ok_or("Failed to get guild")?;
+
+            let user_ids = gis::role_holders(&cols, &g, *removed_role_id);
+            gis::refresh_members(&cols, &user_data.presences, &g, user_ids);
         }
         _ => {}
     }
@@ -159,6 +476,23 @@ async fn event_listener(event: &FullEvent, user_data: &Data) -> Result<(), Error
     Ok(())
 }
 
+/// Moves the interested count of a scheduled event
+async fn scheduled_event_interest(
+    cols: &gis::Collections,
+    event_id: &str,
+    guild_id: &str,
+    delta: i64,
+) -> Result<(), Error> {
+    let filter = doc! {"id": event_id, "guild_id": guild_id};
+
+    cols.event
+        .update_one(filter.clone(), doc! {"$inc": {"user_count": delta}}, None)
+        .await?;
+    state::MIRROR.forget(cols.event.name(), &filter);
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     const MAX_CONNECTIONS: u32 = 3; // max connections to the database, we don't need too many here
@@ -179,6 +513,14 @@ async fn main() {
         serenity::all::GatewayIntents::all(), // TODO: Set intents properly
     );
 
+    let client_options = ClientOptions::parse(config::CONFIG.mongodb_url.clone())
+        .await
+        .expect("Error parsing MongoDB URL");
+
+    let mongo = Client::with_options(client_options).expect("Error creating MongoDB client");
+
+    let setup_mongo = mongo.clone();
+
     let framework = poise::Framework::new(
         poise::FrameworkOptions {
             initialize_owners: true,
@@ -214,18 +556,57 @@ async fn main() {
             ..Default::default()
         },
         move |ctx, _ready, _framework| {
+            let mongo = setup_mongo.clone();
+
             Box::pin(async move {
-                let client_options = ClientOptions::parse(config::CONFIG.mongodb_url.clone())
-                    .await
-                    .expect("Error parsing MongoDB URL");
+                let cols = gis::Collections::new(&mongo.database("diswidgets"));
+
+                if let Err(e) = state::warm(&cols).await {
+                    error!("Failed to warm mirror: {}", e);
+                }
+
+                // Must exist before the first write, mongo would create plain collections otherwise
+                if let Err(e) = history::ensure_collections(&cols).await {
+                    error!("Failed to set up presence history collections: {}", e);
+                }
+
+                let presences = Arc::new(writeback::PresenceQueue::default());
+
+                tokio::spawn(presences.clone().run(mongo.database("diswidgets")));
+                tokio::spawn(cleanup::run(mongo.database("diswidgets")));
+
+                let cache_http = CacheHttpImpl {
+                    cache: ctx.cache.clone(),
+                    http: ctx.http.clone(),
+                };
+
+                tokio::spawn(counts::run_recount(mongo.database("diswidgets")));
+                tokio::spawn(history::run(mongo.database("diswidgets")));
+                tokio::spawn(counts::run(
+                    cache_http.clone(),
+                    mongo.database("diswidgets"),
+                ));
+
+                tokio::spawn(reconcile::run(
+                    cache_http.clone(),
+                    mongo.database("diswidgets"),
+                    presences.clone(),
+                ));
+
+                let daily_stats = Arc::new(rollup::DailyStats::default());
+
+                tokio::spawn(
+                    daily_stats
+                        .clone()
+                        .run(cache_http.clone(), mongo.database("diswidgets")),
+                );
 
                 Ok(Data {
-                    cache_http: CacheHttpImpl {
-                        cache: ctx.cache.clone(),
-                        http: ctx.http.clone(),
-                    },
-                    mongo: Client::with_options(client_options)
-                        .expect("Error creating MongoDB client"),
+                    cache_http,
+                    mongo,
+                    presences,
+                    daily_stats,
+                    chunks: chunks::ChunkTracker::default(),
                 })
             })
         },
@@ -236,6 +617,8 @@ async fn main() {
         .await
         .expect("Error creating client");
 
+    tokio::spawn(api::serve(mongo.database("diswidgets")));
+
     if let Err(why) = client.start().await {
         error!("Client error: {:?}", why);
     }
@@ -1,5 +1,7 @@
+use mongodb::bson::DateTime;
 use poise::serenity_prelude::ChannelType;
 use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Server {
@@ -7,7 +9,47 @@ pub struct Server {
     pub id: String,
     pub name: String,
     pub icon: String,
+    #[serde(default)]
     pub member_count: u64,
+    pub banner: Option<String>,
+    pub splash: Option<String>,
+    pub description: Option<String>,
+    /// Boost level, 0 to 3
+    #[serde(default)]
+    pub premium_tier: u8,
+    /// Number of boosts
+    #[serde(default)]
+    pub premium_subscription_count: u64,
+    pub vanity_url_code: Option<String>,
+    /// Raw guild features as sent by discord
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub partnered: bool,
+    #[serde(default)]
+    pub community: bool,
+    #[serde(default)]
+    pub preferred_locale: String,
+    /// Missing on guilds written before creation dates were stored
+    #[serde(default)]
+    pub created_at: Option<DateTime>,
+    /// Members per status, never written by the guild builder as these are maintained incrementally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_counts: Option<StatusCounts>,
+    /// Set when the bot is removed from the guild, the guild is deleted after a grace period
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
+}
+
+/// Counters are moved with ``$inc`` per status, so a guild not recounted yet may lack some of them
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct StatusCounts {
+    pub online: i64,
+    pub idle: i64,
+    pub dnd: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,6 +60,12 @@ pub struct Channels {
     pub channel_type: ChannelType,
     pub category_name: String,
     pub category_id: String,
+    /// Sorting position within the category
+    #[serde(default)]
+    pub position: i64,
+    /// Whether @everyone can view the channel, only public channels are served by the API
+    #[serde(default)]
+    pub public: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,4 +76,143 @@ pub struct User {
     pub discriminator: String,
     pub avatar: String,
     pub status: String,
+    /// Status per platform, a platform the user is not on is unset
+    #[serde(default)]
+    pub client_status: ClientStatus,
+    #[serde(default)]
+    pub activities: Vec<Activity>,
+    /// Role ids of the member
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Highest hoisted role of the member, used to group members like the discord sidebar
+    #[serde(default)]
+    pub hoisted_role: Option<String>,
+    /// Set after a restart or reconnect until the precense is confirmed by fresh guild data
+    #[serde(default)]
+    pub stale: bool,
+    /// When the precense was last written, missing on users written before this was stored
+    #[serde(default)]
+    pub updated_at: Option<DateTime>,
+}
+
+/// A member currently connected to a voice or stage channel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoiceState {
+    /// User ID
+    pub id: String,
+    pub guild_id: String,
+    /// Links to ``Channels.id``
+    pub channel_id: String,
+    pub mute: bool,
+    pub deaf: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub self_stream: bool,
+    pub self_video: bool,
+    /// Suppressed speakers in stage channels are in the audience
+    pub suppress: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Emoji {
+    pub id: String,
+    pub guild_id: String,
+    pub name: String,
+    /// CDN URL of the emoji
+    pub url: String,
+    pub animated: bool,
+    /// False if the emoji was lost to a loss of server boosts
+    pub available: bool,
+    /// Managed by an integration
+    pub managed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sticker {
+    pub id: String,
+    pub guild_id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// One of png, apng, lottie or gif
+    pub format: String,
+    /// CDN URL of the sticker
+    pub url: Option<String>,
+    pub available: bool,
+}
+
+/// A guild scheduled event
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Event {
+    pub id: String,
+    pub guild_id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub start_time: DateTime,
+    pub end_time: Option<DateTime>,
+    /// Location of external events
+    pub location: Option<String>,
+    /// Voice or stage channel of non-external events
+    pub channel_id: Option<String>,
+    /// One of scheduled, active, completed or canceled
+    pub status: String,
+    /// Number of interested members
+    ///
+    /// Only sent by discord when fetching events, otherwise this is maintained by user add/remove events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_count: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Role {
+    pub id: String,
+    pub guild_id: String,
+    pub name: String,
+    /// RGB color, 0 if the role has no color
+    pub color: u32,
+    pub position: i64,
+    pub hoist: bool,
+    pub icon: Option<String>,
+    pub unicode_emoji: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ClientStatus {
+    pub desktop: Option<String>,
+    pub mobile: Option<String>,
+    pub web: Option<String>,
+}
+
+/// A platform members can be filtered by
+#[derive(Debug, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Platform {
+    Desktop,
+    Mobile,
+    Web,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Activity {
+    /// One of playing, streaming, listening, watching, custom or competing
+    pub kind: String,
+    pub name: String,
+    pub details: Option<String>,
+    pub state: Option<String>,
+    /// Stream URL, only set on streaming activities
+    pub url: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub started_at: Option<u64>,
+    /// Unix timestamp in milliseconds
+    pub ends_at: Option<u64>,
+    /// Emoji of a custom status
+    pub emoji: Option<ActivityEmoji>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActivityEmoji {
+    pub name: String,
+    /// Only set for custom emojis
+    pub id: Option<String>,
+    pub animated: bool,
 }
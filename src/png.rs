@@ -0,0 +1,77 @@
+/// PNG rasterization of the SVG widgets, for platforms that won't show SVG
+///
+/// Rendered images are cached by a hash of the stored guild data they were rendered from, so a
+/// change to the guild makes for a new key and old images age out of the cache
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use axum::body::Bytes;
+use log::info;
+use once_cell::sync::Lazy;
+use usvg_text_layout::{fontdb, TreeTextToPath};
+
+use crate::{config, lru::Lru, svg, widget::Widget, Error};
+
+static FONTS: Lazy<fontdb::Database> = Lazy::new(|| {
+    let mut fonts = fontdb::Database::new();
+    fonts.load_system_fonts();
+
+    info!("Loaded {} fonts", fonts.len());
+
+    fonts
+});
+
+/// Rendered images keyed by ``cache_key``
+static CACHE: Lazy<Mutex<Lru<u64, Bytes>>> =
+    Lazy::new(|| Mutex::new(Lru::new(config::CONFIG.png_cache_max_entries, None)));
+
+/// Hash of everything an image is rendered from, the server includes the guild id
+pub fn cache_key(widget: &Widget, options: &svg::Options) -> Result<u64, Error> {
+    let mut hasher = DefaultHasher::new();
+
+    serde_json::to_vec(&(&widget.server, &widget.members, widget.presence_count))?
+        .hash(&mut hasher);
+    options.variant.to_string().hash(&mut hasher);
+    options.theme.to_string().hash(&mut hasher);
+    options.size.to_string().hash(&mut hasher);
+
+    Ok(hasher.finish())
+}
+
+pub fn cached(key: u64) -> Option<Bytes> {
+    CACHE.lock().unwrap().get(&key)
+}
+
+pub fn store(key: u64, png: Bytes) {
+    CACHE.lock().unwrap().insert(key, png);
+}
+
+pub fn image_count() -> usize {
+    CACHE.lock().unwrap().len()
+}
+
+/// Rasterizes an SVG on the blocking pool
+pub async fn rasterize(svg: String) -> Result<Bytes, Error> {
+    tokio::task::spawn_blocking(move || -> Result<Bytes, Error> {
+        let mut tree = usvg::Tree::from_str(&svg, &usvg::Options::default())?;
+        tree.convert_text(&FONTS, false);
+
+        let size = tree.size.to_screen_size();
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+            .ok_or("Failed to allocate pixmap")?;
+
+        resvg::render(
+            &tree,
+            usvg::FitTo::Original,
+            tiny_skia::Transform::default(),
+            pixmap.as_mut(),
+        )
+        .ok_or("Failed to render image")?;
+
+        Ok(Bytes::from(pixmap.encode_png()?))
+    })
+    .await?
+}
@@ -0,0 +1,35 @@
+/// Filters used to read the ``bot__`` collections back
+use mongodb::bson::{doc, Document};
+
+use crate::models::Platform;
+
+/// Members of a guild, optionally only those currently on ``platform``
+pub fn members(guild_id: &str, platform: Option<Platform>) -> Document {
+    let mut filter = doc! {"guild_id": guild_id};
+
+    if let Some(platform) = platform {
+        filter.insert(format!("client_status.{}", platform), doc! {"$ne": null});
+    }
+
+    filter
+}
+
+/// Members of a guild that are online, idle or on do not disturb, and confirmed since the last
+/// reconnect
+pub fn online_members(guild_id: &str) -> Document {
+    doc! {
+        "guild_id": guild_id,
+        "status": {"$in": ["online", "idle", "dnd"]},
+        "stale": {"$ne": true},
+    }
+}
+
+/// Channels of a guild that @everyone can view
+pub fn public_channels(guild_id: &str) -> Document {
+    doc! {"guild_id": guild_id, "public": true}
+}
+
+/// Events of a guild that have not ended yet
+pub fn upcoming_events(guild_id: &str) -> Document {
+    doc! {"guild_id": guild_id, "status": {"$in": ["scheduled", "active"]}}
+}
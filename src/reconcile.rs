@@ -0,0 +1,226 @@
+/// Periodic repair of drift between the serenity cache and mongo
+///
+/// Missed gateway events and failed writes leave documents wrong forever otherwise. For every
+/// cached guild, the stored server and user documents are compared with what the cache says:
+/// missing users are inserted, mismatched documents are fixed and users without a cached
+/// precense are deleted (in large guilds, only if they are no longer a member)
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::{StreamExt, TryStreamExt};
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Database,
+};
+use poise::serenity_prelude::{GuildId, UserId};
+
+use crate::{
+    bulk,
+    cache::CacheHttpImpl,
+    config, gis,
+    state::{self, MIRROR},
+    writeback::PresenceQueue,
+    Error,
+};
+
+/// Metrics of a reconciliation run
+#[derive(Default, Clone, Debug)]
+pub struct RunMetrics {
+    pub guilds: u64,
+    pub failed_guilds: u64,
+    pub servers_fixed: u64,
+    pub users_inserted: u64,
+    pub users_updated: u64,
+    pub users_deleted: u64,
+    pub duration: Duration,
+}
+
+impl RunMetrics {
+    fn add(&mut self, other: &RunMetrics) {
+        self.guilds += other.guilds;
+        self.failed_guilds += other.failed_guilds;
+        self.servers_fixed += other.servers_fixed;
+        self.users_inserted += other.users_inserted;
+        self.users_updated += other.users_updated;
+        self.users_deleted += other.users_deleted;
+    }
+}
+
+/// Metrics of the last finished run
+pub static LAST_RUN: Mutex<Option<RunMetrics>> = Mutex::new(None);
+
+async fn reconcile_guild(
+    cache_http: &CacheHttpImpl,
+    cols: &gis::Collections,
+    presences: &PresenceQueue,
+    guild_id: GuildId,
+) -> Result<RunMetrics, Error> {
+    let mut metrics = RunMetrics {
+        guilds: 1,
+        ..Default::default()
+    };
+
+    let gid = guild_id.to_string();
+
+    // Server document
+    let server_filter = doc! {"id": &gid};
+
+    match cols.server.find_one(server_filter.clone(), None).await? {
+        None => {
+            gis::add_or_update(
+                &cols.server,
+                server_filter,
+                gis::guild(cache_http, guild_id)?,
+            )
+            .await?;
+            metrics.servers_fixed += 1;
+        }
+        Some(mut stored) => {
+            let expected = gis::into_document(gis::guild_info(cache_http, guild_id)?)?;
+
+            if !state::matches(&expected, &stored) {
+                stored.remove("_id");
+                // Let the mirror know what is actually stored, so the write is not skipped
+                MIRROR.record(cols.server.name(), &server_filter, stored);
+
+                gis::add_or_update(&cols.server, server_filter, Bson::Document(expected)).await?;
+                metrics.servers_fixed += 1;
+            }
+        }
+    }
+
+    // User documents
+    let (expected, large, members) = {
+        let g = guild_id
+            .to_guild_cached(&cache_http.cache)
+            .ok_or("Failed to get guild")?;
+
+        let mut expected = HashMap::new();
+
+        for (user_id, precense) in g.presences.iter() {
+            match gis::user_precense(&g, precense).and_then(gis::into_document) {
+                Ok(document) => {
+                    expected.insert(*user_id, document);
+                }
+                Err(e) => error!("Failed to create bson document for precense: {}", e),
+            }
+        }
+
+        (
+            expected,
+            g.large,
+            g.members.keys().copied().collect::<HashSet<_>>(),
+        )
+    };
+
+    // Repairs go through the queue like every other user write. Flushes are held off while the
+    // mirror is corrected from what is stored, so the queued repairs move the counters exactly once
+    let _paused = presences.pause().await;
+
+    let mut stored = HashMap::new();
+    let mut cursor = cols.user.find(doc! {"guild_id": &gid}, None).await?;
+
+    while let Some(mut document) = cursor.try_next().await? {
+        document.remove("_id");
+
+        if let Ok(id) = document.get_str("id") {
+            stored.insert(id.to_string(), document);
+        }
+    }
+
+    for (user_id, document) in expected.iter() {
+        let id = user_id.to_string();
+        let filter = doc! {"id": &id, "guild_id": &gid};
+
+        match stored.get(&id) {
+            None => {
+                MIRROR.forget(cols.user.name(), &filter);
+                metrics.users_inserted += 1;
+                presences.push(guild_id, *user_id, document.clone());
+            }
+            Some(current) if !state::matches(document, current) => {
+                MIRROR.record(cols.user.name(), &filter, current.clone());
+                metrics.users_updated += 1;
+                presences.push(guild_id, *user_id, document.clone());
+            }
+            _ => {}
+        }
+    }
+
+    for (id, current) in stored.into_iter() {
+        let user_id = match id.parse::<u64>() {
+            Ok(user_id) => UserId::new(user_id),
+            Err(_) => continue,
+        };
+
+        // Large guilds only have precenses cached once they change, so only drop members that left
+        if expected.contains_key(&user_id) || (large && members.contains(&user_id)) {
+            continue;
+        }
+
+        let filter = bulk::key_filter(&current, &["id", "guild_id"])?;
+
+        MIRROR.record(cols.user.name(), &filter, current);
+        presences.remove(guild_id, user_id);
+        metrics.users_deleted += 1;
+    }
+
+    Ok(metrics)
+}
+
+/// Reconciles every cached guild, at most ``reconcile_max_concurrency`` at a time
+pub async fn reconcile(
+    cache_http: &CacheHttpImpl,
+    cols: &gis::Collections,
+    presences: &PresenceQueue,
+) -> RunMetrics {
+    let start = Instant::now();
+    let totals = Mutex::new(RunMetrics::default());
+
+    futures_util::stream::iter(cache_http.cache.guilds())
+        .for_each_concurrent(
+            config::CONFIG.reconcile_max_concurrency.max(1),
+            |guild_id| {
+                let totals = &totals;
+
+                async move {
+                    match reconcile_guild(cache_http, cols, presences, guild_id).await {
+                        Ok(metrics) => totals.lock().unwrap().add(&metrics),
+                        Err(e) => {
+                            error!("Failed to reconcile guild: gid={}, err={}", guild_id, e);
+                            totals.lock().unwrap().failed_guilds += 1;
+                        }
+                    }
+                }
+            },
+        )
+        .await;
+
+    let mut metrics = totals.into_inner().unwrap();
+    metrics.duration = start.elapsed();
+
+    info!("Reconciliation finished: {:?}", metrics);
+
+    metrics
+}
+
+pub async fn run(cache_http: CacheHttpImpl, db: Database, presences: Arc<PresenceQueue>) {
+    let cols = gis::Collections::new(&db);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config::CONFIG.reconcile_interval_secs));
+
+    // The first tick completes immediately, guilds are freshly snapshotted at that point anyways
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let metrics = reconcile(&cache_http, &cols, &presences).await;
+
+        *LAST_RUN.lock().unwrap() = Some(metrics);
+    }
+}
@@ -0,0 +1,201 @@
+/// Daily guild statistics in ``bot__server_stats_daily``
+///
+/// Joins, leaves and messages are counted in memory as events come in. Every
+/// ``stats_rollup_interval_secs`` the counters are flushed into the document of the current
+/// (UTC) day, along with the member count, peak online members and voice minutes sampled
+/// from the cache
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    options::UpdateOptions,
+    Database,
+};
+use poise::serenity_prelude::{ChannelId, GuildId, OnlineStatus};
+
+use crate::{cache::CacheHttpImpl, config, gis, Error};
+
+/// Counters of a guild for a single day
+#[derive(Default)]
+struct DayCounters {
+    joins: i64,
+    leaves: i64,
+    messages: HashMap<ChannelId, i64>,
+}
+
+impl DayCounters {
+    fn merge(&mut self, other: DayCounters) {
+        self.joins += other.joins;
+        self.leaves += other.leaves;
+
+        for (channel_id, count) in other.messages {
+            *self.messages.entry(channel_id).or_default() += count;
+        }
+    }
+}
+
+/// The current UTC day as ``YYYY-MM-DD``
+fn today() -> String {
+    DateTime::now()
+        .try_to_rfc3339_string()
+        .unwrap_or_default()
+        .chars()
+        .take(10)
+        .collect()
+}
+
+#[derive(Default)]
+pub struct DailyStats {
+    pending: Mutex<HashMap<(GuildId, String), DayCounters>>,
+}
+
+impl DailyStats {
+    fn update(&self, guild_id: GuildId, f: impl FnOnce(&mut DayCounters)) {
+        f(self
+            .pending
+            .lock()
+            .unwrap()
+            .entry((guild_id, today()))
+            .or_default());
+    }
+
+    pub fn member_joined(&self, guild_id: GuildId) {
+        self.update(guild_id, |c| c.joins += 1);
+    }
+
+    pub fn member_left(&self, guild_id: GuildId) {
+        self.update(guild_id, |c| c.leaves += 1);
+    }
+
+    pub fn message(&self, guild_id: GuildId, channel_id: ChannelId) {
+        self.update(guild_id, |c| {
+            *c.messages.entry(channel_id).or_default() += 1
+        });
+    }
+
+    /// Flushes all counters and samples the cache, ``elapsed`` is the time since the last flush
+    pub async fn flush(
+        &self,
+        cache_http: &CacheHttpImpl,
+        cols: &gis::Collections,
+        elapsed: Duration,
+    ) -> Result<(), Error> {
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let date = today();
+
+        for guild_id in cache_http.cache.guilds() {
+            // Copy what we need out of the cache, the guild must not be held across an await
+            let (member_count, online, in_voice) = match guild_id.to_guild_cached(&cache_http.cache)
+            {
+                Some(g) => (
+                    g.member_count,
+                    g.presences
+                        .values()
+                        .filter(|p| p.status != OnlineStatus::Offline)
+                        .count(),
+                    g.voice_states
+                        .values()
+                        .filter(|v| v.channel_id.is_some())
+                        .count(),
+                ),
+                None => continue,
+            };
+
+            let counters = pending
+                .remove(&(guild_id, date.clone()))
+                .unwrap_or_default();
+
+            let mut inc = counters_inc(&counters);
+            inc.insert(
+                "voice_minutes",
+                in_voice as f64 * elapsed.as_secs_f64() / 60.0,
+            );
+
+            let res = cols
+                .stats_daily
+                .update_one(
+                    doc! {"guild_id": guild_id.to_string(), "date": &date},
+                    doc! {
+                        "$inc": inc,
+                        "$max": {"peak_online": online as i64},
+                        "$set": {"member_count": member_count as i64},
+                    },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await;
+
+            if let Err(e) = res {
+                error!("Failed to roll up stats: gid={}, err={}", guild_id, e);
+                self.restore(guild_id, date.clone(), counters);
+            }
+        }
+
+        // Counters of guilds no longer cached or of a day that has already ended
+        for ((guild_id, date), counters) in pending {
+            let res = cols
+                .stats_daily
+                .update_one(
+                    doc! {"guild_id": guild_id.to_string(), "date": &date},
+                    doc! {"$inc": counters_inc(&counters)},
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await;
+
+            if let Err(e) = res {
+                error!("Failed to roll up stats: gid={}, err={}", guild_id, e);
+                self.restore(guild_id, date, counters);
+            }
+        }
+
+        info!("Rolled up daily stats for {}", date);
+
+        Ok(())
+    }
+
+    /// Puts counters that failed to flush back
+    fn restore(&self, guild_id: GuildId, date: String, counters: DayCounters) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry((guild_id, date))
+            .or_default()
+            .merge(counters);
+    }
+
+    pub async fn run(self: Arc<Self>, cache_http: CacheHttpImpl, db: Database) {
+        let cols = gis::Collections::new(&db);
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            config::CONFIG.stats_rollup_interval_secs,
+        ));
+
+        // The first tick completes immediately, there is nothing to roll up yet
+        interval.tick().await;
+        let mut last = Instant::now();
+
+        loop {
+            interval.tick().await;
+
+            let elapsed = last.elapsed();
+            last = Instant::now();
+
+            if let Err(e) = self.flush(&cache_http, &cols, elapsed).await {
+                error!("Failed to roll up daily stats: {}", e);
+            }
+        }
+    }
+}
+
+fn counters_inc(counters: &DayCounters) -> Document {
+    let mut inc = doc! {"joins": counters.joins, "leaves": counters.leaves};
+
+    for (channel_id, count) in counters.messages.iter() {
+        inc.insert(format!("messages.{}", channel_id), *count);
+    }
+
+    inc
+}
@@ -0,0 +1,173 @@
+/// In-process mirror of what the bot last wrote to mongo
+///
+/// Entries are keyed by collection and filter. Knowing what is already stored lets writes
+/// skip the existence check, and skip mongo entirely when nothing changed
+use std::{collections::HashMap, sync::RwLock};
+
+use futures_util::TryStreamExt;
+use log::info;
+use mongodb::{
+    bson::{Bson, Document},
+    Collection,
+};
+use once_cell::sync::Lazy;
+
+use crate::{bulk, gis, Error};
+
+/// Global mirror object
+pub static MIRROR: Lazy<Mirror> = Lazy::new(Mirror::default);
+
+/// Fields that change on every write and are ignored when comparing documents
+const VOLATILE_FIELDS: [&str; 1] = ["updated_at"];
+
+/// Returns true if every field of ``expected`` is stored as is, ignoring volatile fields
+///
+/// Writes only ``$set`` fields, so fields that are stored but not expected don't matter
+pub fn matches(expected: &Document, stored: &Document) -> bool {
+    expected
+        .iter()
+        .all(|(k, v)| VOLATILE_FIELDS.contains(&k.as_str()) || stored.get(k) == Some(v))
+}
+
+#[derive(Default)]
+pub struct Mirror {
+    /// collection name -> filter -> last written document
+    entries: RwLock<HashMap<String, HashMap<String, Document>>>,
+}
+
+impl Mirror {
+    fn key(filter: &Document) -> String {
+        filter.to_string()
+    }
+
+    /// Returns true if ``document`` is what was last written for this filter, ignoring volatile fields
+    pub fn unchanged(&self, col: &str, filter: &Document, document: &Document) -> bool {
+        self.entries
+            .read()
+            .unwrap()
+            .get(col)
+            .and_then(|entries| entries.get(&Self::key(filter)))
+            .map(|last| matches(document, last))
+            .unwrap_or(false)
+    }
+
+    /// Returns the last written document for this filter
+    pub fn get(&self, col: &str, filter: &Document) -> Option<Document> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(col)
+            .and_then(|entries| entries.get(&Self::key(filter)))
+            .cloned()
+    }
+
+    /// Returns every last written document of a collection matching ``predicate``
+    pub fn find(&self, col: &str, predicate: impl Fn(&Document) -> bool) -> Vec<Document> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(col)
+            .map(|entries| entries.values().filter(|d| predicate(d)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records a document as written
+    pub fn record(&self, col: &str, filter: &Document, document: Document) {
+        self.entries
+            .write()
+            .unwrap()
+            .entry(col.to_string())
+            .or_default()
+            .insert(Self::key(filter), document);
+    }
+
+    /// Drops an entry, the next write for this filter will always go through
+    pub fn forget(&self, col: &str, filter: &Document) {
+        if let Some(entries) = self.entries.write().unwrap().get_mut(col) {
+            entries.remove(&Self::key(filter));
+        }
+    }
+
+    /// Drops every entry of a collection whose ``field`` equals ``value``
+    pub fn forget_where(&self, col: &str, field: &str, value: &str) {
+        if let Some(entries) = self.entries.write().unwrap().get_mut(col) {
+            entries.retain(|_, document| document.get_str(field).ok() != Some(value));
+        }
+    }
+
+    /// Sets ``set_field`` on every entry of a collection whose ``field`` equals ``value``
+    pub fn set_where(&self, col: &str, field: &str, value: &str, set_field: &str, set_value: Bson) {
+        if let Some(entries) = self.entries.write().unwrap().get_mut(col) {
+            for document in entries.values_mut() {
+                if document.get_str(field).ok() == Some(value) {
+                    document.insert(set_field, set_value.clone());
+                }
+            }
+        }
+    }
+
+    /// Number of documents mirrored across all collections
+    pub fn document_count(&self) -> usize {
+        self.entries.read().unwrap().values().map(|e| e.len()).sum()
+    }
+}
+
+/// Loads every document of a collection into the mirror
+async fn warm_collection(col: &Collection<Document>, keys: &[&str]) -> Result<usize, Error> {
+    let mut cursor = col.find(None, None).await?;
+    let mut count = 0;
+
+    while let Some(mut document) = cursor.try_next().await? {
+        document.remove("_id");
+
+        let filter = match bulk::key_filter(&document, keys) {
+            Ok(filter) => filter,
+            Err(_) => continue,
+        };
+
+        MIRROR.record(col.name(), &filter, document);
+        count += 1;
+    }
+
+    info!("Warmed mirror (col={}): {} documents", col.name(), count);
+
+    Ok(count)
+}
+
+/// Warms the mirror from all collections, this should be done once at startup
+pub async fn warm(cols: &gis::Collections) -> Result<(), Error> {
+    warm_collection(&cols.server, &["id"]).await?;
+    warm_collection(&cols.user, &["id", "guild_id"]).await?;
+    warm_collection(&cols.channel, &["id", "guild_id"]).await?;
+    warm_collection(&cols.role, &["id", "guild_id"]).await?;
+    warm_collection(&cols.voice, &["id", "guild_id"]).await?;
+    warm_collection(&cols.emoji, &["id", "guild_id"]).await?;
+    warm_collection(&cols.sticker, &["id", "guild_id"]).await?;
+    warm_collection(&cols.event, &["id", "guild_id"]).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, DateTime};
+
+    use super::*;
+
+    #[test]
+    fn matches_ignores_volatile_and_extra_fields() {
+        let stored = doc! {
+            "id": "1",
+            "status": "online",
+            "updated_at": DateTime::from_millis(0),
+            "extra": 1,
+        };
+
+        assert!(matches(
+            &doc! {"id": "1", "status": "online", "updated_at": DateTime::now()},
+            &stored
+        ));
+        assert!(!matches(&doc! {"id": "1", "status": "idle"}, &stored));
+        assert!(!matches(&doc! {"id": "1", "missing": true}, &stored));
+    }
+}
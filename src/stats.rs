@@ -14,6 +14,9 @@ pub const RUSTC_VERSION: &str = env!("VERGEN_RUSTC_SEMVER");
 
 #[poise::command(category = "Stats", prefix_command, slash_command, user_cooldown = 1)]
 pub async fn stats(ctx: Context<'_>) -> Result<(), Error> {
+    let queue = ctx.data().presences.stats();
+    let reconcile = crate::reconcile::LAST_RUN.lock().unwrap().clone();
+
     let msg = CreateReply::default().embed(
         CreateEmbed::default()
             .title("Bot Stats")
@@ -26,7 +29,55 @@ pub async fn stats(ctx: Context<'_>) -> Result<(), Error> {
             )
             .field("Commit Message", GIT_COMMIT_MSG, true)
             .field("Built On", BUILD_CPU, true)
-            .field("Cargo Profile", CARGO_PROFILE, true),
+            .field("Cargo Profile", CARGO_PROFILE, true)
+            .field(
+                "Precense Queue",
+                format!(
+                    "depth={}, flushes={}, written={}, coalesced={}",
+                    ctx.data().presences.depth(),
+                    queue.flushes,
+                    queue.written,
+                    queue.coalesced
+                ),
+                true,
+            )
+            .field(
+                "Mirrored Documents",
+                crate::state::MIRROR.document_count().to_string(),
+                true,
+            )
+            .field("Cached Images", crate::png::image_count().to_string(), true)
+            .field("Cached Avatars", crate::svg::image_count().to_string(), true)
+            .field(
+                "Last Flush",
+                format!(
+                    "{} docs in {:?}",
+                    queue.last_flush_size, queue.last_flush_latency
+                ),
+                true,
+            )
+            .field(
+                "Guilds Chunking",
+                ctx.data().chunks.in_progress().to_string(),
+                true,
+            )
+            .field(
+                "Last Reconciliation",
+                match reconcile {
+                    Some(r) => format!(
+                        "{} guilds ({} failed) in {:?}: servers fixed={}, users inserted={}, updated={}, deleted={}",
+                        r.guilds,
+                        r.failed_guilds,
+                        r.duration,
+                        r.servers_fixed,
+                        r.users_inserted,
+                        r.users_updated,
+                        r.users_deleted
+                    ),
+                    None => "Not run yet".to_string(),
+                },
+                false,
+            ),
     );
 
     ctx.send(msg).await?;
@@ -0,0 +1,573 @@
+/// Server rendered widget images, for places that can't run javascript
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+use data_encoding::BASE64;
+use futures_util::future::join_all;
+use log::warn;
+use once_cell::sync::Lazy;
+use strum_macros::{Display, EnumString};
+
+use crate::{
+    config,
+    lru::Lru,
+    widget::{self, Widget},
+};
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("Error creating HTTP client")
+});
+
+/// Data URIs of fetched images by URL, ``None`` for images the CDN doesn't serve
+static IMAGES: Lazy<Mutex<Lru<String, Option<String>>>> = Lazy::new(|| {
+    Mutex::new(Lru::new(
+        config::CONFIG.image_cache_max_entries,
+        Some(Duration::from_secs(config::CONFIG.image_cache_ttl_secs)),
+    ))
+});
+
+#[derive(Debug, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Size {
+    Small,
+    Medium,
+    Large,
+}
+
+struct Palette {
+    background: &'static str,
+    text: &'static str,
+    muted: &'static str,
+    button: &'static str,
+}
+
+impl Theme {
+    fn palette(self) -> Palette {
+        match self {
+            Theme::Dark => Palette {
+                background: "#2b2d31",
+                text: "#f2f3f5",
+                muted: "#b5bac1",
+                button: "#248046",
+            },
+            Theme::Light => Palette {
+                background: "#ffffff",
+                text: "#060607",
+                muted: "#4e5058",
+                button: "#248046",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Variant {
+    /// Card with an avatar grid and a join button
+    Widget,
+    /// Wide banner with a single row of avatars
+    Banner,
+}
+
+impl Size {
+    /// Width of the widget and size of a member avatar
+    fn dimensions(self) -> (u32, u32) {
+        match self {
+            Size::Small => (280, 28),
+            Size::Medium => (350, 32),
+            Size::Large => (450, 40),
+        }
+    }
+
+    /// Width and height of the banner and size of a member avatar
+    fn banner_dimensions(self) -> (u32, u32, u32) {
+        match self {
+            Size::Small => (480, 120, 28),
+            Size::Medium => (640, 150, 32),
+            Size::Large => (800, 180, 40),
+        }
+    }
+}
+
+pub struct Options {
+    pub variant: Variant,
+    pub theme: Theme,
+    pub size: Size,
+}
+
+impl Options {
+    /// Avatar slots, the overflow counter takes one of them
+    fn slots(&self) -> u32 {
+        match self.variant {
+            Variant::Widget => {
+                let (width, avatar) = self.size.dimensions();
+                avatars_per_row(width, avatar) * 2
+            }
+            Variant::Banner => banner_layout(self.size).per_row,
+        }
+    }
+
+    /// Avatars shown when no member limit is given, two rows on widgets and one on banners
+    pub fn default_member_limit(&self) -> i64 {
+        i64::from(self.slots() - 1)
+    }
+
+    /// Banners only have room for a single row
+    pub fn max_member_limit(&self) -> i64 {
+        match self.variant {
+            Variant::Widget => widget::MAX_MEMBERS,
+            Variant::Banner => self.default_member_limit(),
+        }
+    }
+}
+
+/// Images of a widget as data URIs, keyed by their URL
+///
+/// Images are embedded since an SVG shown through ``<img>`` can't load anything itself
+pub type Images = HashMap<String, String>;
+
+fn status_color(status: &str) -> &'static str {
+    match status {
+        "online" => "#23a55a",
+        "idle" => "#f0b232",
+        "dnd" => "#f23f43",
+        _ => "#80848e",
+    }
+}
+
+/// Escapes text for use in element content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Asks the CDN for a small png, as not every renderer can decode webp
+fn small_png(url: &str, size: u32) -> String {
+    let path = url.split('?').next().unwrap_or(url);
+
+    let path = match path.rsplit_once('/') {
+        Some((dir, file)) => match file.rsplit_once('.') {
+            Some((stem, _)) => format!("{}/{}", dir, stem),
+            None => path.to_string(),
+        },
+        None => path.to_string(),
+    };
+
+    format!("{}.png?size={}", path, size)
+}
+
+/// Fetches an image as a data URI, ``None`` if the CDN doesn't serve it
+async fn fetch_data_uri(url: &str) -> Result<Option<String>, reqwest::Error> {
+    let response = HTTP.get(small_png(url, 64)).send().await?;
+
+    if !response.status().is_success() {
+        warn!(
+            "Failed to fetch widget image: url={}, status={}",
+            url,
+            response.status()
+        );
+        return Ok(None);
+    }
+
+    let bytes = response.bytes().await?;
+
+    Ok(Some(format!(
+        "data:image/png;base64,{}",
+        BASE64.encode(&bytes)
+    )))
+}
+
+/// Returns the data URI of an image, fetching it unless it is cached
+///
+/// Images the CDN doesn't serve are cached as ``None``, failed requests are not cached and
+/// return an error so they are retried next time
+async fn data_uri(url: String) -> Result<Option<(String, String)>, reqwest::Error> {
+    let cached = IMAGES.lock().unwrap().get(&url);
+
+    let uri = match cached {
+        Some(uri) => uri,
+        None => {
+            let uri = match fetch_data_uri(&url).await {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Failed to fetch widget image: url={}, err={}", url, e);
+                    return Err(e);
+                }
+            };
+
+            IMAGES.lock().unwrap().insert(url.clone(), uri.clone());
+            uri
+        }
+    };
+
+    Ok(uri.map(|uri| (url, uri)))
+}
+
+/// Number of fetched images currently cached
+pub fn image_count() -> usize {
+    IMAGES.lock().unwrap().len()
+}
+
+/// Fetches the guild icon and member avatars of a widget
+///
+/// Also returns whether every request succeeded, images missing because of a failed request
+/// are drawn as placeholders and may be there next time
+pub async fn fetch_images(widget: &Widget) -> (Images, bool) {
+    let mut urls = vec![widget.server.icon.clone()];
+    urls.extend(widget.members.iter().map(|m| m.avatar.clone()));
+    urls.sort();
+    urls.dedup();
+
+    let mut images = Images::new();
+    let mut complete = true;
+
+    for result in join_all(urls.into_iter().map(data_uri)).await {
+        match result {
+            Ok(Some((url, uri))) => {
+                images.insert(url, uri);
+            }
+            Ok(None) => {}
+            Err(_) => complete = false,
+        }
+    }
+
+    (images, complete)
+}
+
+fn avatars_per_row(width: u32, avatar: u32) -> u32 {
+    ((width - 32 + 8) / (avatar + 8)).max(1)
+}
+
+/// Where member avatars are drawn
+struct Strip {
+    x: u32,
+    y: u32,
+    avatar: u32,
+    per_row: u32,
+}
+
+impl Strip {
+    /// Slots taken by the shown members plus the overflow counter, if any
+    fn slots(&self, widget: &Widget) -> u32 {
+        widget.members.len() as u32 + u32::from(hidden_members(widget) > 0)
+    }
+
+    fn position(&self, slot: u32) -> (u32, u32) {
+        (
+            self.x + (slot % self.per_row) * (self.avatar + 8),
+            self.y + (slot / self.per_row) * (self.avatar + 8),
+        )
+    }
+}
+
+/// Online members that are not shown
+fn hidden_members(widget: &Widget) -> i64 {
+    (widget.presence_count - widget.members.len() as i64).max(0)
+}
+
+struct BannerLayout {
+    width: u32,
+    height: u32,
+    icon: u32,
+    avatar: u32,
+    /// Start of the text and avatar column, right of the icon
+    text_x: u32,
+    per_row: u32,
+}
+
+fn banner_layout(size: Size) -> BannerLayout {
+    let (width, height, avatar) = size.banner_dimensions();
+    let icon = height - 32;
+    let text_x = 16 + icon + 20;
+
+    BannerLayout {
+        width,
+        height,
+        icon,
+        avatar,
+        text_x,
+        per_row: ((width - text_x - 24 + 8) / (avatar + 8)).max(1),
+    }
+}
+
+/// Shortens text to ``max`` characters
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+
+    let mut truncated = text.chars().take(max - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+/// Draws a circular image, or a placeholder circle if the image could not be fetched
+fn circle_image(
+    svg: &mut String,
+    id: &str,
+    href: Option<&String>,
+    x: u32,
+    y: u32,
+    d: u32,
+    fill: &str,
+) {
+    let r = d / 2;
+
+    match href {
+        Some(href) => {
+            let _ = write!(
+                svg,
+                r#"<clipPath id="{id}"><circle cx="{cx}" cy="{cy}" r="{r}"/></clipPath><image x="{x}" y="{y}" width="{d}" height="{d}" clip-path="url(#{id})" xlink:href="{href}"/>"#,
+                cx = x + r,
+                cy = y + r,
+            );
+        }
+        None => {
+            let _ = write!(
+                svg,
+                r#"<circle cx="{cx}" cy="{cy}" r="{r}" fill="{fill}"/>"#,
+                cx = x + r,
+                cy = y + r,
+            );
+        }
+    }
+}
+
+/// Draws member avatars with their status, followed by a counter of the members not shown
+fn avatar_strip(
+    svg: &mut String,
+    widget: &Widget,
+    images: &Images,
+    palette: &Palette,
+    strip: &Strip,
+) {
+    let avatar = strip.avatar;
+
+    for (i, member) in widget.members.iter().enumerate() {
+        let (x, y) = strip.position(i as u32);
+
+        circle_image(
+            svg,
+            &format!("m{}", i),
+            images.get(&member.avatar),
+            x,
+            y,
+            avatar,
+            palette.muted,
+        );
+
+        let dot = avatar / 6 + 1;
+        let _ = write!(
+            svg,
+            r#"<circle cx="{cx}" cy="{cy}" r="{dot}" fill="{}" stroke="{}" stroke-width="2"/>"#,
+            status_color(&member.status),
+            palette.background,
+            cx = x + avatar - dot,
+            cy = y + avatar - dot,
+        );
+    }
+
+    let hidden = hidden_members(widget);
+
+    if hidden > 0 {
+        let (x, y) = strip.position(widget.members.len() as u32);
+        let r = avatar / 2;
+
+        let _ = write!(
+            svg,
+            r#"<circle cx="{cx}" cy="{cy}" r="{r}" fill="{}"/><text x="{cx}" y="{ty}" font-size="11" text-anchor="middle" fill="{}">+{}</text>"#,
+            palette.muted,
+            palette.background,
+            hidden,
+            cx = x + r,
+            cy = y + r,
+            ty = y + r + 4,
+        );
+    }
+}
+
+fn open(svg: &mut String, width: u32, height: u32, palette: &Palette) {
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="Helvetica, Arial, sans-serif">"#,
+    );
+    let _ = write!(
+        svg,
+        r#"<rect width="{width}" height="{height}" rx="8" fill="{}"/>"#,
+        palette.background
+    );
+}
+
+/// Renders a widget or banner
+///
+/// ``widget.members`` should already be limited to the members that are to be shown
+pub fn render(widget: &Widget, images: &Images, options: &Options) -> String {
+    match options.variant {
+        Variant::Widget => render_widget(widget, images, options),
+        Variant::Banner => render_banner(widget, images, options),
+    }
+}
+
+/// Icon, name and online count, an avatar grid and a join button
+fn render_widget(widget: &Widget, images: &Images, options: &Options) -> String {
+    let palette = options.theme.palette();
+    let (width, avatar) = options.size.dimensions();
+
+    let strip = Strip {
+        x: 16,
+        y: 80,
+        avatar,
+        per_row: avatars_per_row(width, avatar),
+    };
+    let rows = strip.slots(widget).div_ceil(strip.per_row);
+
+    let invite = widget.instant_invite();
+
+    let button_y = strip.y + rows * (avatar + 8) + 8;
+    let height = match invite {
+        Some(_) => button_y + 36 + 16,
+        None => button_y,
+    };
+
+    let mut svg = String::new();
+
+    open(&mut svg, width, height, &palette);
+
+    // Header
+    circle_image(
+        &mut svg,
+        "icon",
+        images.get(&widget.server.icon),
+        16,
+        16,
+        48,
+        palette.muted,
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="76" y="38" font-size="16" font-weight="bold" fill="{}">{}</text>"#,
+        palette.text,
+        escape(&widget.server.name)
+    );
+    let _ = write!(
+        svg,
+        r#"<circle cx="80" cy="55" r="4" fill="{}"/><text x="90" y="59" font-size="13" fill="{}">{} Online</text>"#,
+        status_color("online"),
+        palette.muted,
+        widget.presence_count
+    );
+
+    avatar_strip(&mut svg, widget, images, &palette, &strip);
+
+    // Join button
+    if let Some(invite) = invite {
+        let _ = write!(
+            svg,
+            r##"<a xlink:href="{}" target="_blank"><rect x="16" y="{button_y}" width="{}" height="36" rx="4" fill="{}"/><text x="{}" y="{}" font-size="14" font-weight="bold" text-anchor="middle" fill="#ffffff">Join Server</text></a>"##,
+            escape(&invite),
+            width - 32,
+            palette.button,
+            width / 2,
+            button_y + 23,
+        );
+    }
+
+    svg.push_str("</svg>");
+
+    svg
+}
+
+/// Large icon on the left, with the name, online and member counts and a row of avatars next to it
+fn render_banner(widget: &Widget, images: &Images, options: &Options) -> String {
+    let palette = options.theme.palette();
+    let layout = banner_layout(options.size);
+
+    let mut svg = String::new();
+
+    open(&mut svg, layout.width, layout.height, &palette);
+
+    circle_image(
+        &mut svg,
+        "icon",
+        images.get(&widget.server.icon),
+        16,
+        16,
+        layout.icon,
+        palette.muted,
+    );
+
+    let invite = widget.instant_invite();
+
+    // Leave room for the join button
+    let name_len = match invite {
+        Some(_) => (layout.width - layout.text_x - 120) / 13,
+        None => (layout.width - layout.text_x - 24) / 13,
+    };
+
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="42" font-size="22" font-weight="bold" fill="{}">{}</text>"#,
+        layout.text_x,
+        palette.text,
+        escape(&truncate(&widget.server.name, name_len as usize))
+    );
+    let _ = write!(
+        svg,
+        r#"<circle cx="{}" cy="63" r="4" fill="{}"/><text x="{}" y="68" font-size="15" fill="{}">{} Online · {} Members</text>"#,
+        layout.text_x + 4,
+        status_color("online"),
+        layout.text_x + 14,
+        palette.muted,
+        widget.presence_count,
+        widget.server.member_count
+    );
+
+    avatar_strip(
+        &mut svg,
+        widget,
+        images,
+        &palette,
+        &Strip {
+            x: layout.text_x,
+            y: layout.height - 16 - layout.avatar,
+            avatar: layout.avatar,
+            per_row: layout.per_row,
+        },
+    );
+
+    if let Some(invite) = invite {
+        let _ = write!(
+            svg,
+            r##"<a xlink:href="{}" target="_blank"><rect x="{}" y="20" width="80" height="30" rx="4" fill="{}"/><text x="{}" y="40" font-size="14" font-weight="bold" text-anchor="middle" fill="#ffffff">Join</text></a>"##,
+            escape(&invite),
+            layout.width - 24 - 80,
+            palette.button,
+            layout.width - 24 - 40,
+        );
+    }
+
+    svg.push_str("</svg>");
+
+    svg
+}
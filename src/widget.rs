@@ -0,0 +1,210 @@
+/// Widget data shared by the widget endpoints, built from the ``bot__`` collections
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+use poise::serenity_prelude::ChannelType;
+use serde::Serialize;
+
+use crate::{gis, models, query, Error};
+
+/// Discord caps the members of its widget at 100
+pub const MAX_MEMBERS: i64 = 100;
+
+/// Everything a widget is rendered from
+pub struct Widget {
+    pub server: models::Server,
+    /// Online members, sorted by name
+    pub members: Vec<models::User>,
+    pub channels: Vec<models::Channels>,
+    pub voice: Vec<models::VoiceState>,
+    /// Total members online, not capped like ``members``
+    pub presence_count: i64,
+}
+
+/// Loads the widget of a guild, returns None if the bot is not in the guild
+pub async fn load(
+    cols: &gis::Collections,
+    guild_id: &str,
+    member_limit: i64,
+) -> Result<Option<Widget>, Error> {
+    let server = match cols
+        .server
+        .clone_with_type::<models::Server>()
+        .find_one(doc! {"id": guild_id, "deleted_at": null}, None)
+        .await?
+    {
+        Some(server) => server,
+        None => return Ok(None),
+    };
+
+    let members: Vec<models::User> = cols
+        .user
+        .clone_with_type::<models::User>()
+        .find(
+            query::online_members(guild_id),
+            FindOptions::builder()
+                .sort(doc! {"name": 1})
+                .limit(member_limit)
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    let channels: Vec<models::Channels> = cols
+        .channel
+        .clone_with_type::<models::Channels>()
+        .find(
+            query::public_channels(guild_id),
+            FindOptions::builder().sort(doc! {"position": 1}).build(),
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    // Members in channels that aren't public must not give those channels away
+    let voice: Vec<models::VoiceState> = cols
+        .voice
+        .clone_with_type::<models::VoiceState>()
+        .find(
+            doc! {
+                "guild_id": guild_id,
+                "channel_id": {"$in": channels.iter().map(|c| c.id.as_str()).collect::<Vec<_>>()},
+            },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    // Status counters are missing until a precense of a new guild is written
+    let presence_count = match &server.status_counts {
+        Some(counts) => counts.online + counts.idle + counts.dnd,
+        None => {
+            cols.user
+                .count_documents(query::online_members(guild_id), None)
+                .await? as i64
+        }
+    };
+
+    Ok(Some(Widget {
+        server,
+        members,
+        channels,
+        voice,
+        presence_count,
+    }))
+}
+
+/// Same schema as discord's ``/api/guilds/{id}/widget.json``
+#[derive(Serialize)]
+pub struct WidgetJson {
+    pub id: String,
+    pub name: String,
+    pub instant_invite: Option<String>,
+    pub channels: Vec<WidgetChannel>,
+    pub members: Vec<WidgetMember>,
+    pub presence_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct WidgetChannel {
+    pub id: String,
+    pub name: String,
+    pub position: i64,
+}
+
+#[derive(Serialize)]
+pub struct WidgetMember {
+    /// Index of the member in the widget, discord does not expose user ids here
+    pub id: String,
+    pub username: String,
+    pub discriminator: String,
+    pub avatar: Option<String>,
+    pub status: String,
+    pub avatar_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub game: Option<WidgetGame>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deaf: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_deaf: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_mute: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppress: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct WidgetGame {
+    pub name: String,
+}
+
+impl Widget {
+    /// Invite link of the guild, only vanity invites are known to the bot
+    pub fn instant_invite(&self) -> Option<String> {
+        self.server
+            .vanity_url_code
+            .as_ref()
+            .map(|code| format!("https://discord.com/invite/{}", code))
+    }
+
+    pub fn to_json(&self) -> WidgetJson {
+        let voice = self
+            .voice
+            .iter()
+            .map(|v| (v.id.as_str(), v))
+            .collect::<HashMap<_, _>>();
+
+        WidgetJson {
+            id: self.server.id.clone(),
+            name: self.server.name.clone(),
+            instant_invite: self.instant_invite(),
+            // Like discord, only voice channels are listed
+            channels: self
+                .channels
+                .iter()
+                .filter(|c| matches!(c.channel_type, ChannelType::Voice | ChannelType::Stage))
+                .map(|c| WidgetChannel {
+                    id: c.id.clone(),
+                    name: c.name.clone(),
+                    position: c.position,
+                })
+                .collect(),
+            members: self
+                .members
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    let v = voice.get(m.id.as_str());
+
+                    WidgetMember {
+                        id: i.to_string(),
+                        username: m.name.clone(),
+                        discriminator: "0000".to_string(),
+                        avatar: None,
+                        status: m.status.clone(),
+                        avatar_url: m.avatar.clone(),
+                        game: m.activities.iter().find(|a| a.kind != "custom").map(|a| {
+                            WidgetGame {
+                                name: a.name.clone(),
+                            }
+                        }),
+                        channel_id: v.map(|v| v.channel_id.clone()),
+                        deaf: v.map(|v| v.deaf),
+                        mute: v.map(|v| v.mute),
+                        self_deaf: v.map(|v| v.self_deaf),
+                        self_mute: v.map(|v| v.self_mute),
+                        suppress: v.map(|v| v.suppress),
+                    }
+                })
+                .collect(),
+            presence_count: self.presence_count,
+        }
+    }
+}
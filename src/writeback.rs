@@ -0,0 +1,235 @@
+/// Write-behind queue for precense updates
+///
+/// Precenses are buffered per (guild, user) and only the latest state of each is kept
+/// until the next flush, which happens on a timer or once the queue gets too deep.
+///
+/// Removals of members go through the queue as well, so that every write of a user document
+/// happens in the order it was queued and a flush in flight can't bring back a removed member
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    Database,
+};
+use poise::serenity_prelude::{GuildId, UserId};
+use tokio::sync::Notify;
+
+use crate::{config, counts, gis, state::MIRROR, Error};
+
+/// Counters describing how the queue has been doing
+#[derive(Default, Clone, Debug)]
+pub struct QueueStats {
+    /// Number of flushes that wrote at least one document
+    pub flushes: u64,
+    /// Documents written across all flushes
+    pub written: u64,
+    /// Updates that replaced a still pending update for the same user
+    pub coalesced: u64,
+    pub last_flush_size: usize,
+    pub last_flush_latency: Duration,
+}
+
+/// Pending write of a user document
+enum Write {
+    Upsert(Document),
+    Delete,
+}
+
+#[derive(Default)]
+pub struct PresenceQueue {
+    pending: Mutex<HashMap<(GuildId, UserId), Write>>,
+    stats: Mutex<QueueStats>,
+    flush_now: Notify,
+    /// Held for the duration of a flush, so batches are written one after the other
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl PresenceQueue {
+    /// Queues the latest precense document of a user, replacing any pending write
+    pub fn push(&self, guild_id: GuildId, user_id: UserId, document: Document) {
+        self.queue(guild_id, user_id, Write::Upsert(document));
+    }
+
+    /// Queues the removal of a member, replacing any pending write
+    pub fn remove(&self, guild_id: GuildId, user_id: UserId) {
+        self.queue(guild_id, user_id, Write::Delete);
+    }
+
+    fn queue(&self, guild_id: GuildId, user_id: UserId, write: Write) {
+        let depth = {
+            let mut pending = self.pending.lock().unwrap();
+
+            if pending.insert((guild_id, user_id), write).is_some() {
+                self.stats.lock().unwrap().coalesced += 1;
+            }
+
+            pending.len()
+        };
+
+        if depth >= config::CONFIG.presence_flush_threshold {
+            self.flush_now.notify_one();
+        }
+    }
+
+    /// Holds off flushes until the guard is dropped
+    ///
+    /// Used by callers that correct the mirror from what is stored before queueing repairs, so no
+    /// flush can write or read those mirror entries in between
+    pub async fn pause(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.flushing.lock().await
+    }
+
+    /// Queues members of a guild whose precense is still stale as offline
+    ///
+    /// Called once all precenses of a guild were received, a precense that wasn't confirmed by
+    /// then belongs to a member that went offline while the bot was away
+    pub async fn expire_stale(&self, cols: &gis::Collections, guild_id: GuildId) -> usize {
+        // A flush between taking a batch and recording it would hide confirmed precenses
+        let _paused = self.pause().await;
+
+        let gid = guild_id.to_string();
+        let stale = MIRROR.find(cols.user.name(), |d| {
+            d.get_str("guild_id") == Ok(gid.as_str()) && d.get_bool("stale") == Ok(true)
+        });
+
+        let mut expired = 0;
+
+        for mut document in stale {
+            let user_id = match document.get_str("id").ok().and_then(|id| id.parse().ok()) {
+                Some(user_id) => UserId::new(user_id),
+                None => continue,
+            };
+
+            // Confirmed by a precense that isn't written yet
+            if self
+                .pending
+                .lock()
+                .unwrap()
+                .contains_key(&(guild_id, user_id))
+            {
+                continue;
+            }
+
+            document.insert("status", "offline");
+            document.insert("client_status", Document::new());
+            document.insert("activities", Bson::Array(vec![]));
+            document.insert("stale", false);
+            document.insert("updated_at", DateTime::now());
+
+            self.push(guild_id, user_id, document);
+            expired += 1;
+        }
+
+        if expired > 0 {
+            info!(
+                "Expired stale precenses: gid={}, count={}",
+                guild_id, expired
+            );
+        }
+
+        expired
+    }
+
+    /// Number of writes waiting to be flushed
+    pub fn depth(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Puts writes that failed back, unless a newer write was queued in the meantime
+    fn requeue(&self, writes: impl IntoIterator<Item = ((GuildId, UserId), Write)>) {
+        let mut pending = self.pending.lock().unwrap();
+
+        for (key, write) in writes {
+            pending.entry(key).or_insert(write);
+        }
+    }
+
+    /// Writes out everything currently queued, upserts in one bulk write followed by removals
+    pub async fn flush(&self, cols: &gis::Collections) -> Result<(), Error> {
+        let _flushing = self.flushing.lock().await;
+
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let size = batch.len();
+
+        let (upserts, deletes): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|(_, write)| matches!(write, Write::Upsert(_)));
+
+        let docs = upserts
+            .iter()
+            .filter_map(|(_, write)| match write {
+                Write::Upsert(document) => Some(document.clone()),
+                Write::Delete => None,
+            })
+            .collect::<Vec<_>>();
+
+        if let Err(e) = counts::upsert_users(cols, docs).await {
+            self.requeue(upserts.into_iter().chain(deletes));
+            return Err(e);
+        }
+
+        let deletes = deletes.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+        for (i, &(guild_id, user_id)) in deletes.iter().enumerate() {
+            let filter = doc! {"id": user_id.to_string(), "guild_id": guild_id.to_string()};
+
+            if let Err(e) = counts::delete_user(cols, filter).await {
+                self.requeue(deletes[i..].iter().map(|&key| (key, Write::Delete)));
+                return Err(e);
+            }
+        }
+
+        let latency = start.elapsed();
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.flushes += 1;
+            stats.written += size as u64;
+            stats.last_flush_size = size;
+            stats.last_flush_latency = latency;
+        }
+
+        info!(
+            "Flushed precense queue: size={}, latency={:?}, depth={}",
+            size,
+            latency,
+            self.depth()
+        );
+
+        Ok(())
+    }
+
+    /// Flushes the queue forever, on every tick or whenever the queue hits its threshold
+    pub async fn run(self: Arc<Self>, db: Database) {
+        let cols = gis::Collections::new(&db);
+        let mut interval = tokio::time::interval(Duration::from_millis(
+            config::CONFIG.presence_flush_interval_ms,
+        ));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.flush_now.notified() => {}
+            }
+
+            if let Err(e) = self.flush(&cols).await {
+                error!("Failed to flush precense queue: {}", e);
+            }
+        }
+    }
+}